@@ -1,6 +1,6 @@
 use std::{
     fmt::{Display, Formatter},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -9,6 +9,16 @@ pub enum ServerAddr {
     DomainName(String, u16),
 }
 
+impl ServerAddr {
+    /// create a server address from a host which is either an ip address or a domain name
+    pub fn new(host: &str, port: u16) -> Self {
+        match host.parse::<IpAddr>() {
+            Ok(ip) => ServerAddr::SocketAddr(SocketAddr::new(ip, port)),
+            Err(_) => ServerAddr::DomainName(host.to_string(), port),
+        }
+    }
+}
+
 impl Display for ServerAddr {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -17,3 +27,12 @@ impl Display for ServerAddr {
         }
     }
 }
+
+impl From<ServerAddr> for vmess::Address {
+    fn from(addr: ServerAddr) -> Self {
+        match addr {
+            ServerAddr::SocketAddr(addr) => vmess::Address::SocketAddr(addr),
+            ServerAddr::DomainName(domain, port) => vmess::Address::DomainName(domain, port),
+        }
+    }
+}
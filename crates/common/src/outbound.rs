@@ -0,0 +1,121 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use log::debug;
+use tokio::net::{lookup_host, TcpStream, UdpSocket};
+use vmess::{mux::MuxClient, stream::VMESSStream, udp::VMESSUdpSession, VmessUser};
+
+use crate::{
+    net::ServerAddr,
+    proxy::{ProxyClientStream, ProxyUdpSession},
+};
+
+/// tag of the built-in outbound which connects to the target directly
+pub const DIRECT: &str = "DIRECT";
+
+#[derive(Debug, Clone)]
+pub enum Outbound {
+    Direct,
+    Vmess {
+        addr: ServerAddr,
+        user: VmessUser,
+    },
+    /// VMess with the streams multiplexed over shared connections
+    VmessMux(Arc<MuxClient>),
+}
+
+impl Outbound {
+    /// open a stream to target through this outbound
+    pub async fn connect(&self, target: &ServerAddr) -> io::Result<ProxyClientStream> {
+        debug!("Connecting to {} via {:?}", target, self);
+        match self {
+            Outbound::Direct => {
+                let stream = match target {
+                    ServerAddr::SocketAddr(addr) => TcpStream::connect(addr).await?,
+                    ServerAddr::DomainName(domain, port) => {
+                        TcpStream::connect((domain.as_str(), *port)).await?
+                    }
+                };
+                Ok(ProxyClientStream::DIRECT(stream))
+            }
+            Outbound::Vmess { addr, user } => Ok(ProxyClientStream::VMESS(Box::new(
+                VMESSStream::connect(addr.to_string(), target.clone().into(), user).await?,
+            ))),
+            Outbound::VmessMux(client) => Ok(ProxyClientStream::MUX(
+                client.connect(target.clone().into()).await?,
+            )),
+        }
+    }
+
+    /// open a datagram session to target through this outbound
+    pub async fn connect_udp(&self, target: &ServerAddr) -> io::Result<ProxyUdpSession> {
+        debug!("Connecting udp to {} via {:?}", target, self);
+        match self {
+            Outbound::Direct => {
+                let addr = match target {
+                    ServerAddr::SocketAddr(addr) => *addr,
+                    ServerAddr::DomainName(domain, port) => lookup_host((domain.as_str(), *port))
+                        .await?
+                        .next()
+                        .ok_or_else(|| {
+                            io::Error::new(
+                                io::ErrorKind::NotFound,
+                                format!("Failed to resolve {}", domain),
+                            )
+                        })?,
+                };
+                let bind_addr: SocketAddr = match addr {
+                    SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                    SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                };
+                let socket = UdpSocket::bind(bind_addr).await?;
+                socket.connect(addr).await?;
+                Ok(ProxyUdpSession::DIRECT(socket))
+            }
+            Outbound::Vmess { addr, user } => Ok(ProxyUdpSession::VMESS(Box::new(
+                VMESSUdpSession::connect(addr.to_string(), target.clone().into(), user).await?,
+            ))),
+            Outbound::VmessMux(client) => Ok(ProxyUdpSession::MUX(
+                client.connect_udp(target.clone().into()).await?,
+            )),
+        }
+    }
+}
+
+/// Outbounds keyed by their tag
+#[derive(Debug, Clone)]
+pub struct OutboundRegistry {
+    outbounds: HashMap<String, Outbound>,
+}
+
+impl OutboundRegistry {
+    /// create a registry which only contains the `DIRECT` outbound
+    pub fn new() -> Self {
+        let mut outbounds = HashMap::new();
+        outbounds.insert(DIRECT.to_string(), Outbound::Direct);
+        Self { outbounds }
+    }
+
+    /// register an outbound, replace the old one with the same tag
+    pub fn insert(&mut self, tag: impl Into<String>, outbound: Outbound) -> Option<Outbound> {
+        self.outbounds.insert(tag.into(), outbound)
+    }
+
+    pub fn get(&self, tag: &str) -> Option<&Outbound> {
+        self.outbounds.get(tag)
+    }
+
+    pub fn contains(&self, tag: &str) -> bool {
+        self.outbounds.contains_key(tag)
+    }
+}
+
+impl Default for OutboundRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
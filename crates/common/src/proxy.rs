@@ -1,15 +1,20 @@
-use std::{io, net::SocketAddr, pin::Pin, task};
+use std::{io, net::SocketAddr, pin::Pin, sync::Arc, task};
 
 /// Taken from shadowsocks-rust
 use tokio::{
-    io::{AsyncRead, AsyncWrite, ReadBuf},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf},
+    net::{TcpStream, UdpSocket},
+};
+use vmess::{
+    mux::MuxStream,
+    stream::VMESSStream,
+    udp::{VMESSUdpRecvHalf, VMESSUdpSendHalf, VMESSUdpSession},
 };
-use vmess::stream::VMESSStream;
 
 pub enum ProxyClientStream {
     DIRECT(TcpStream),
-    VMESS(VMESSStream),
+    VMESS(Box<VMESSStream>),
+    MUX(MuxStream),
 }
 impl ProxyClientStream {
     /// local address of the stream client
@@ -17,6 +22,7 @@ impl ProxyClientStream {
         match self {
             ProxyClientStream::DIRECT(stream) => stream.local_addr(),
             ProxyClientStream::VMESS(stream) => stream.local_addr(),
+            ProxyClientStream::MUX(stream) => stream.local_addr(),
         }
     }
 
@@ -25,6 +31,7 @@ impl ProxyClientStream {
         match self {
             ProxyClientStream::DIRECT(_) => 1 << 14,
             ProxyClientStream::VMESS(vmess_stream) => vmess_stream.buffer_size(),
+            ProxyClientStream::MUX(mux_stream) => mux_stream.buffer_size(),
         }
     }
 }
@@ -38,6 +45,7 @@ impl AsyncWrite for ProxyClientStream {
         match self.get_mut() {
             ProxyClientStream::DIRECT(direct_stream) => Pin::new(direct_stream).poll_write(cx, buf),
             ProxyClientStream::VMESS(vmess_stream) => Pin::new(vmess_stream).poll_write(cx, buf),
+            ProxyClientStream::MUX(mux_stream) => Pin::new(mux_stream).poll_write(cx, buf),
         }
     }
 
@@ -45,6 +53,7 @@ impl AsyncWrite for ProxyClientStream {
         match self.get_mut() {
             ProxyClientStream::DIRECT(direct_stream) => Pin::new(direct_stream).poll_flush(cx),
             ProxyClientStream::VMESS(vmess_stream) => Pin::new(vmess_stream).poll_flush(cx),
+            ProxyClientStream::MUX(mux_stream) => Pin::new(mux_stream).poll_flush(cx),
         }
     }
 
@@ -55,6 +64,7 @@ impl AsyncWrite for ProxyClientStream {
         match self.get_mut() {
             ProxyClientStream::DIRECT(direct_stream) => Pin::new(direct_stream).poll_shutdown(cx),
             ProxyClientStream::VMESS(vmess_stream) => Pin::new(vmess_stream).poll_shutdown(cx),
+            ProxyClientStream::MUX(mux_stream) => Pin::new(mux_stream).poll_shutdown(cx),
         }
     }
 }
@@ -68,6 +78,86 @@ impl AsyncRead for ProxyClientStream {
         match self.get_mut() {
             ProxyClientStream::DIRECT(direct_stream) => Pin::new(direct_stream).poll_read(cx, buf),
             ProxyClientStream::VMESS(vmess_stream) => Pin::new(vmess_stream).poll_read(cx, buf),
+            ProxyClientStream::MUX(mux_stream) => Pin::new(mux_stream).poll_read(cx, buf),
+        }
+    }
+}
+
+/// A datagram session to one target through an outbound
+pub enum ProxyUdpSession {
+    /// a udp socket connected to the target
+    DIRECT(UdpSocket),
+    VMESS(Box<VMESSUdpSession>),
+    /// a udp sub-stream of a mux connection
+    MUX(MuxStream),
+}
+
+impl ProxyUdpSession {
+    /// split the session so that datagrams can be sent and received concurrently
+    pub fn split(self) -> (ProxyUdpSendHalf, ProxyUdpRecvHalf) {
+        match self {
+            ProxyUdpSession::DIRECT(socket) => {
+                let socket = Arc::new(socket);
+                (
+                    ProxyUdpSendHalf::DIRECT(socket.clone()),
+                    ProxyUdpRecvHalf::DIRECT(socket),
+                )
+            }
+            ProxyUdpSession::VMESS(session) => {
+                let (send, recv) = (*session).split();
+                (ProxyUdpSendHalf::VMESS(send), ProxyUdpRecvHalf::VMESS(recv))
+            }
+            ProxyUdpSession::MUX(stream) => {
+                let (recv, send) = tokio::io::split(stream);
+                (ProxyUdpSendHalf::MUX(send), ProxyUdpRecvHalf::MUX(recv))
+            }
+        }
+    }
+}
+
+/// Sending half of a `ProxyUdpSession`
+pub enum ProxyUdpSendHalf {
+    DIRECT(Arc<UdpSocket>),
+    VMESS(VMESSUdpSendHalf),
+    MUX(WriteHalf<MuxStream>),
+}
+
+impl ProxyUdpSendHalf {
+    /// send one datagram to the target, empty datagrams are dropped
+    pub async fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        if datagram.is_empty() {
+            return Ok(());
+        }
+        match self {
+            ProxyUdpSendHalf::DIRECT(socket) => socket.send(datagram).await.map(|_| ()),
+            ProxyUdpSendHalf::VMESS(send) => send.send(datagram).await,
+            // each write is carried by one frame
+            ProxyUdpSendHalf::MUX(send) => send.write_all(datagram).await,
+        }
+    }
+}
+
+/// Receiving half of a `ProxyUdpSession`
+pub enum ProxyUdpRecvHalf {
+    DIRECT(Arc<UdpSocket>),
+    VMESS(VMESSUdpRecvHalf),
+    MUX(ReadHalf<MuxStream>),
+}
+
+impl ProxyUdpRecvHalf {
+    /// receive one datagram from the target, it is truncated if buf is too small,
+    /// return 0 when the session is closed
+    pub async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            // empty datagrams are dropped, so that 0 only means closed
+            ProxyUdpRecvHalf::DIRECT(socket) => loop {
+                let n = socket.recv(buf).await?;
+                if n > 0 {
+                    return Ok(n);
+                }
+            },
+            ProxyUdpRecvHalf::VMESS(recv) => recv.recv(buf).await,
+            ProxyUdpRecvHalf::MUX(recv) => recv.read(buf).await,
         }
     }
 }
@@ -1 +1,398 @@
+//! Config file loading
+//!
+//! The config file is JSON with comments, see `assets/config.jsonc` for an example.
 
+use std::{fmt, fs::File, io, path::Path, sync::Arc, time::Duration};
+
+use common::{
+    net::ServerAddr,
+    outbound::{Outbound, OutboundRegistry},
+};
+use json_comments::StripComments;
+use serde::Deserialize;
+use socks::{Authentication, Users};
+use uuid::Uuid;
+use vmess::{
+    env::Env,
+    mux::{MuxClient, MuxConfig},
+    Encryption, VMESSOptions, VmessUser,
+};
+
+/// top level config
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    /// tag of the default outbound, used by every inbound without an override
+    pub outbound: String,
+    /// inbounds
+    pub local: Vec<LocalConfig>,
+    /// tagged outbounds
+    #[serde(default)]
+    pub remote: Vec<RemoteConfig>,
+}
+
+impl Config {
+    /// load config from a JSONC file
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let config = serde_json::from_reader(StripComments::new(file))?;
+        Ok(config)
+    }
+
+    /// build the outbound registry from `remote`, `DIRECT` is always available
+    pub fn outbounds(&self) -> io::Result<OutboundRegistry> {
+        let mut outbounds = OutboundRegistry::new();
+        for remote in self.remote.iter() {
+            if outbounds
+                .insert(&remote.tag, remote.to_outbound()?)
+                .is_some()
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Duplicate outbound tag: {}", remote.tag),
+                ));
+            }
+        }
+        Ok(outbounds)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LocalProtocol {
+    Socks5,
+    Http,
+    /// relays to the targets directly, the outbound is not used
+    Vmess,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Auth {
+    #[default]
+    NoAuth,
+    /// username/password, the users come from `users` and `users_file`
+    Password,
+}
+
+/// a user of the password auth
+#[derive(Clone, Deserialize)]
+pub struct UserConfig {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for UserConfig {
+    /// the password is not printed
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserConfig")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// an inbound
+#[derive(Clone, Deserialize)]
+pub struct LocalConfig {
+    pub protocol: LocalProtocol,
+    pub address: String,
+    pub port: u16,
+    #[serde(default)]
+    pub auth: Auth,
+    /// override the default outbound
+    pub outbound: Option<String>,
+    /// uuids of the clients allowed by a vmess inbound
+    #[serde(default)]
+    pub clients: Vec<String>,
+    /// number of alter ids of the clients, a vmess inbound accepts their legacy header
+    /// if it is not 0
+    #[serde(default)]
+    pub alter_id: u16,
+    /// users of the password auth
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+    /// file of `username:password` lines, more users of the password auth
+    pub users_file: Option<String>,
+}
+
+impl fmt::Debug for LocalConfig {
+    /// the uuids of the clients are not printed, they are their credentials
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalConfig")
+            .field("protocol", &self.protocol)
+            .field("address", &self.address)
+            .field("port", &self.port)
+            .field("auth", &self.auth)
+            .field("outbound", &self.outbound)
+            .field("clients", &self.clients.len())
+            .field("alter_id", &self.alter_id)
+            .field("users", &self.users)
+            .field("users_file", &self.users_file)
+            .finish()
+    }
+}
+
+impl LocalConfig {
+    /// listen address of the inbound
+    pub fn addr(&self) -> String {
+        format!("{}:{}", self.address, self.port)
+    }
+
+    /// tag of the outbound used by this inbound
+    pub fn outbound<'a>(&'a self, default: &'a str) -> &'a str {
+        self.outbound.as_deref().unwrap_or(default)
+    }
+
+    /// authentication of a socks inbound
+    pub fn socks_auth(&self) -> io::Result<Authentication> {
+        match self.auth {
+            Auth::NoAuth => Ok(Authentication::NoAuth),
+            Auth::Password => {
+                let mut users = Users::new();
+                for user in self.users.iter() {
+                    users.insert(&user.username, &user.password);
+                }
+                if let Some(path) = &self.users_file {
+                    users.extend(Users::load(path).map_err(|e| {
+                        io::Error::new(
+                            e.kind(),
+                            format!("Inbound {}: failed to load {}: {}", self.addr(), path, e),
+                        )
+                    })?);
+                }
+                if users.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Inbound {}: password auth without users", self.addr()),
+                    ));
+                }
+                Ok(Authentication::UserPass(Arc::new(users)))
+            }
+        }
+    }
+
+    /// parse the uuids of the clients
+    pub fn clients(&self) -> io::Result<Vec<Uuid>> {
+        self.clients
+            .iter()
+            .map(|client| {
+                Uuid::parse_str(client).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Inbound {}: invalid uuid {}: {}", self.addr(), client, e),
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RemoteProtocol {
+    Vmess,
+}
+
+/// body security of a VMess outbound
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Security {
+    #[default]
+    Auto,
+    None,
+    #[serde(rename = "aes-128-gcm")]
+    Aes128Gcm,
+    #[serde(rename = "chacha20-poly1305")]
+    Chacha20Poly1305,
+}
+
+impl From<Security> for Encryption {
+    fn from(security: Security) -> Self {
+        match security {
+            Security::Auto => Encryption::AUTO,
+            Security::None => Encryption::NONE,
+            Security::Aes128Gcm => Encryption::AES128GCM,
+            Security::Chacha20Poly1305 => Encryption::CHACHA20POLY1305,
+        }
+    }
+}
+
+/// optional features of a VMess outbound
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VmessOption {
+    /// mask the chunk length
+    ChunkMasking,
+    /// append random padding to each chunk, requires `chunk_masking`
+    GlobalPadding,
+    /// encrypt the chunk length, only for aead securities
+    AuthenticatedLength,
+}
+
+impl From<VmessOption> for VMESSOptions {
+    fn from(option: VmessOption) -> Self {
+        match option {
+            VmessOption::ChunkMasking => VMESSOptions::M,
+            VmessOption::GlobalPadding => VMESSOptions::P,
+            VmessOption::AuthenticatedLength => VMESSOptions::A,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    #[default]
+    Tcp,
+}
+
+/// Mux.Cool settings of a VMess outbound
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct MuxSettings {
+    /// max number of streams on one connection
+    #[serde(default = "MuxSettings::default_concurrency")]
+    pub concurrency: usize,
+    /// seconds before a connection without any stream is closed
+    #[serde(default = "MuxSettings::default_idle_timeout")]
+    pub idle_timeout: u64,
+}
+
+impl MuxSettings {
+    fn default_concurrency() -> usize {
+        MuxConfig::default().concurrency
+    }
+
+    fn default_idle_timeout() -> u64 {
+        MuxConfig::default().idle_timeout.as_secs()
+    }
+}
+
+impl From<MuxSettings> for MuxConfig {
+    fn from(settings: MuxSettings) -> Self {
+        MuxConfig {
+            concurrency: settings.concurrency,
+            idle_timeout: Duration::from_secs(settings.idle_timeout),
+        }
+    }
+}
+
+/// an outbound, referenced by its tag
+#[derive(Clone, Deserialize)]
+pub struct RemoteConfig {
+    pub tag: String,
+    pub protocol: RemoteProtocol,
+    pub address: String,
+    pub port: u16,
+    pub uuid: String,
+    #[serde(default)]
+    pub security: Security,
+    #[serde(default)]
+    pub options: Vec<VmessOption>,
+    /// number of alter ids, the legacy header is used if it is not 0
+    #[serde(default)]
+    pub alter_id: u16,
+    /// seconds added to the local time in the handshakes, for a clock drifting from the server's
+    #[serde(default)]
+    pub clock_offset: i64,
+    #[serde(default)]
+    pub network: Network,
+    #[serde(default)]
+    pub tls: bool,
+    /// multiplex the streams over shared connections
+    pub mux: Option<MuxSettings>,
+}
+
+impl fmt::Debug for RemoteConfig {
+    /// the uuid is not printed, it is the credential of the outbound
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RemoteConfig")
+            .field("tag", &self.tag)
+            .field("protocol", &self.protocol)
+            .field("address", &self.address)
+            .field("port", &self.port)
+            .field("security", &self.security)
+            .field("options", &self.options)
+            .field("alter_id", &self.alter_id)
+            .field("clock_offset", &self.clock_offset)
+            .field("network", &self.network)
+            .field("tls", &self.tls)
+            .field("mux", &self.mux)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RemoteConfig {
+    pub fn to_outbound(&self) -> io::Result<Outbound> {
+        if self.tls {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Outbound {}: tls is not supported", self.tag),
+            ));
+        }
+        match (self.protocol, self.network) {
+            (RemoteProtocol::Vmess, Network::Tcp) => {
+                let uuid = Uuid::parse_str(&self.uuid).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Outbound {}: invalid uuid {}: {}", self.tag, self.uuid, e),
+                    )
+                })?;
+                let addr = ServerAddr::new(&self.address, self.port);
+                let mut user = VmessUser::new(uuid)
+                    .with_alter_id(self.alter_id)
+                    .with_security(self.security.into());
+                user.options = self.options.iter().map(|&o| o.into()).collect();
+                user.env = Env::default().with_clock_offset(self.clock_offset);
+                user.validate().map_err(|e| {
+                    io::Error::new(e.kind(), format!("Outbound {}: {}", self.tag, e))
+                })?;
+                Ok(match self.mux {
+                    Some(mux) => Outbound::VmessMux(Arc::new(MuxClient::new(
+                        addr.to_string(),
+                        user,
+                        mux.into(),
+                    ))),
+                    None => Outbound::Vmess { addr, user },
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UUID: &str = "231c2fc0-f8c4-4248-b098-21f0dd78c810";
+
+    #[test]
+    fn debug_hides_the_credentials() {
+        let config = format!(
+            r#"{{
+                "outbound": "vmess",
+                "local": [
+                    {{
+                        "protocol": "socks5", "address": "127.0.0.1", "port": 1080,
+                        "auth": "password",
+                        "users": [{{ "username": "alice", "password": "hunter2" }}]
+                    }},
+                    // a vmess server
+                    {{ "protocol": "vmess", "address": "0.0.0.0", "port": 10086, "clients": ["{UUID}"] }}
+                ],
+                "remote": [
+                    {{ "tag": "vmess", "protocol": "vmess", "address": "example.com", "port": 443, "uuid": "{UUID}" }}
+                ]
+            }}"#
+        );
+        let config: Config =
+            serde_json::from_reader(StripComments::new(config.as_bytes())).unwrap();
+        assert_eq!(config.local[0].users[0].password, "hunter2");
+        assert_eq!(config.local[1].clients, [UUID]);
+        assert_eq!(config.remote[0].uuid, UUID);
+
+        let debug = format!("{:?}", config);
+        assert!(debug.contains("alice"), "{}", debug);
+        assert!(debug.contains("clients: 1"), "{}", debug);
+        assert!(!debug.contains("hunter2"), "{}", debug);
+        assert!(!debug.contains(UUID), "{}", debug);
+    }
+}
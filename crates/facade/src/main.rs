@@ -1,6 +1,12 @@
 mod config;
 
-use log::debug;
+use std::sync::Arc;
+
+use clap::{Arg, Command};
+use log::{debug, info, warn};
+use tokio::task::JoinSet;
+
+use crate::config::{Config, LocalProtocol};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -8,10 +14,59 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     debug!("Logging works!");
 
+    let matches = Command::new("facade")
+        .arg(
+            Arg::new("config")
+                .short('c')
+                .long("config")
+                .value_name("FILE")
+                .help("path to the config file")
+                .default_value("assets/config.jsonc"),
+        )
+        .get_matches();
+
     // load config
+    let config_path = matches
+        .get_one::<String>("config")
+        .expect("config has a default value");
+    info!("Loading config from {}", config_path);
+    let config = Config::load(config_path)?;
+    debug!("Config: {:?}", config);
+
+    let outbounds = Arc::new(config.outbounds()?);
+
+    let mut servers = JoinSet::new();
+    for local in config.local.iter() {
+        match local.protocol {
+            LocalProtocol::Socks5 => {
+                let mut socks_server = socks::SocksServer::new(
+                    &local.addr(),
+                    outbounds.clone(),
+                    local.outbound(&config.outbound),
+                )
+                .await?
+                .with_auth(local.socks_auth()?);
+                servers.spawn(async move { socks_server.serve().await });
+            }
+            LocalProtocol::Vmess => {
+                let mut vmess_server = vmess::VmessServer::new(&local.addr(), &local.clients()?)
+                    .await?
+                    .with_alter_id(local.alter_id);
+                servers.spawn(async move { vmess_server.serve().await });
+            }
+            LocalProtocol::Http => {
+                warn!("Http inbound is not supported, skip {}", local.addr());
+            }
+        }
+    }
+
+    if servers.is_empty() {
+        warn!("No inbound to serve");
+    }
 
-    let mut socks_server = socks::SocksServer::new("127.0.0.1:1080").await?;
-    socks_server.serve().await?;
+    while let Some(result) = servers.join_next().await {
+        result??;
+    }
 
     Ok(())
 }
@@ -1,4 +1,16 @@
-use std::fmt::Display;
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    fs, io,
+    path::Path,
+    sync::Arc,
+};
+
+use subtle::ConstantTimeEq;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
 
 use crate::Version;
 
@@ -25,7 +37,7 @@ impl Display for HandshakeResponse {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 #[allow(dead_code)]
 pub(crate) enum AuthMethod {
@@ -42,3 +54,206 @@ impl Default for AuthMethod {
         Self::NoAuth
     }
 }
+
+/// Authentication required by a socks server
+#[derive(Debug, Clone, Default)]
+pub enum Authentication {
+    #[default]
+    NoAuth,
+    /// username/password authentication (RFC 1929)
+    UserPass(Arc<Users>),
+}
+
+impl Authentication {
+    /// methods allowed by the inbound, the preferred one first
+    pub(crate) fn methods(&self) -> &'static [AuthMethod] {
+        match self {
+            Authentication::NoAuth => &[AuthMethod::NoAuth],
+            Authentication::UserPass(_) => &[AuthMethod::UserPass],
+        }
+    }
+}
+
+/// prefixes of the hashes written by htpasswd, only plain text passwords are supported
+const HASHED_PASSWORD_PREFIXES: [&str; 8] = [
+    "$apr1$", "$1$", "$2a$", "$2b$", "$2y$", "$5$", "$6$", "{SHA}",
+];
+
+/// Users allowed by the username/password authentication
+#[derive(Clone, Default)]
+pub struct Users {
+    /// password of each user
+    users: HashMap<String, String>,
+}
+
+impl Users {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// add a user, replace the password if the user exists
+    pub fn insert(&mut self, username: impl Into<String>, password: impl Into<String>) {
+        self.users.insert(username.into(), password.into());
+    }
+
+    /// load the users from a file of `username:password` lines,
+    /// empty lines and lines starting with `#` are skipped, hashed passwords are rejected
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut users = Self::new();
+        for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (username, password) = line.split_once(':').ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}:{}: expect username:password", path.display(), i + 1),
+                )
+            })?;
+            if HASHED_PASSWORD_PREFIXES
+                .iter()
+                .any(|prefix| password.starts_with(prefix))
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{}:{}: hashed passwords are not supported, expect a plain text one",
+                        path.display(),
+                        i + 1
+                    ),
+                ));
+            }
+            users.insert(username, password);
+        }
+        Ok(users)
+    }
+
+    /// add the users of other
+    pub fn extend(&mut self, other: Users) {
+        self.users.extend(other.users);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    /// whether username exists and its password is password,
+    /// the passwords are compared in constant time
+    pub fn check(&self, username: &str, password: &str) -> bool {
+        self.users
+            .get(username)
+            .is_some_and(|expected| expected.as_bytes().ct_eq(password.as_bytes()).into())
+    }
+}
+
+impl fmt::Debug for Users {
+    /// the passwords are not printed
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.users.keys()).finish()
+    }
+}
+
+/// version of the username/password sub-negotiation
+const USER_PASS_VERSION: u8 = 0x01;
+
+/// status of the username/password sub-negotiation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum UserPassStatus {
+    Succeeded = 0x00,
+    Failed = 0x01,
+}
+
+/// username/password request of the client (RFC 1929)
+#[derive(Clone)]
+pub(crate) struct UserPassRequest {
+    pub(crate) username: String,
+    pub(crate) password: String,
+}
+
+impl UserPassRequest {
+    pub(crate) async fn read_from(stream: &mut TcpStream) -> io::Result<Self> {
+        let version = stream.read_u8().await?;
+        if version != USER_PASS_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid username/password version: {}", version),
+            ));
+        }
+        let username = Self::read_field(stream).await?;
+        let password = Self::read_field(stream).await?;
+        Ok(Self { username, password })
+    }
+
+    /// read a field prefixed by its length
+    async fn read_field(stream: &mut TcpStream) -> io::Result<String> {
+        let len = stream.read_u8().await?;
+        let mut buf = vec![0u8; len as usize];
+        stream.read_exact(&mut buf).await?;
+        String::from_utf8(buf).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid username/password: {}", e),
+            )
+        })
+    }
+}
+
+impl fmt::Debug for UserPassRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserPassRequest")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// reply the status of the username/password sub-negotiation
+pub(crate) async fn write_user_pass_status(
+    stream: &mut TcpStream,
+    status: UserPassStatus,
+) -> io::Result<()> {
+    stream.write_all(&[USER_PASS_VERSION, status as u8]).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// load users from a file with the given content
+    fn load(name: &str, content: &str) -> io::Result<Users> {
+        let path =
+            std::env::temp_dir().join(format!("socks-users-{}-{}", std::process::id(), name));
+        fs::write(&path, content).unwrap();
+        let users = Users::load(&path);
+        fs::remove_file(&path).unwrap();
+        users
+    }
+
+    #[test]
+    fn load_plain_passwords() {
+        let users = load("plain", "# jump hosts\n\nalice:secret\nbob:pass:word\n").unwrap();
+        assert_eq!(users.len(), 2);
+        assert!(users.check("alice", "secret"));
+        assert!(users.check("bob", "pass:word"));
+        assert!(!users.check("alice", "secre"));
+        assert!(!users.check("carol", "secret"));
+    }
+
+    #[test]
+    fn load_rejects_hashed_passwords() {
+        for line in [
+            "alice:$apr1$r31.....$HqJZimcKQFAMYayBlzkrA/",
+            "alice:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=",
+            "alice:$2y$05$c4WoMPo3SXsafkva.HHa6uXQZWr7oboPiC2bT/r7q1BB8I2s0BRqC",
+        ] {
+            let e = load("hashed", line).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
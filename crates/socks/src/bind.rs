@@ -0,0 +1,82 @@
+//! BIND
+//!
+//! The client asks the server to accept one connection from the peer it expects,
+//! e.g. the data connection of active FTP, then the connection is relayed to the client.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{
+    net::{lookup_host, TcpListener, TcpStream},
+    time::timeout,
+};
+
+use crate::socks5::Address;
+
+/// how long to wait for the peer to connect
+const BIND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Listener of a BIND request
+pub(crate) struct Binding {
+    listener: TcpListener,
+    expected_peer: Address,
+    /// ips of the expected peer, connections from other hosts are refused
+    expected_ips: Vec<IpAddr>,
+}
+
+impl Binding {
+    /// listen on local_ip for expected_peer, whose port is not checked,
+    /// fail with `HostUnreachable` if the expected peer cannot be resolved
+    pub(crate) async fn bind(local_ip: IpAddr, expected_peer: Address) -> io::Result<Self> {
+        let expected_ips = match &expected_peer {
+            Address::SocketAddr(addr) => vec![addr.ip()],
+            Address::DomainName(domain, port) => lookup_host((domain.as_str(), *port))
+                .await
+                .map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::HostUnreachable,
+                        format!("Failed to resolve {}: {}", domain, e),
+                    )
+                })?
+                .map(|addr| addr.ip())
+                .collect(),
+        };
+        Ok(Self {
+            listener: TcpListener::bind((local_ip, 0)).await?,
+            expected_peer,
+            expected_ips,
+        })
+    }
+
+    /// address the peer should connect to
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// accept one connection, fail with `TimedOut` if the peer does not connect in time
+    /// and with `PermissionDenied` if another host connects
+    pub(crate) async fn accept(self) -> io::Result<(TcpStream, SocketAddr)> {
+        let local_addr = self.listener.local_addr()?;
+        let (stream, peer) = timeout(BIND_TIMEOUT, self.listener.accept())
+            .await
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} did not connect to {}", self.expected_peer, local_addr),
+                )
+            })??;
+        if !self.expected_ips.contains(&peer.ip()) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "{} connected to {}, expected {}",
+                    peer, local_addr, self.expected_peer
+                ),
+            ));
+        }
+        Ok((stream, peer))
+    }
+}
@@ -0,0 +1,108 @@
+//! CONNECT and BIND
+//!
+//! Both versions relay these commands the same way and only differ in their replies,
+//! which are encoded by the `ReplyEncoder` of the version.
+
+use std::{io, net::SocketAddr};
+
+use common::outbound::Outbound;
+use log::{debug, info};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+use crate::{bind::Binding, relay::copy_bidirectional, socks5::Address};
+
+/// Replies of a SOCKS version
+pub(crate) trait ReplyEncoder: Sized {
+    /// the command succeeded, addr is the bound address or the connected peer
+    fn succeeded(addr: SocketAddr) -> Self;
+
+    /// the command failed with error
+    fn failed(error: &io::Error) -> Self;
+
+    /// the command is not supported by this inbound
+    fn not_supported() -> Self;
+
+    fn encode(&self) -> Vec<u8>;
+}
+
+async fn reply<R: ReplyEncoder>(stream: &mut TcpStream, reply: R) -> io::Result<()> {
+    stream.write_all(&reply.encode()).await
+}
+
+/// connect to target through outbound, then relay it to the client
+pub(crate) async fn connect<R: ReplyEncoder>(
+    stream: &mut TcpStream,
+    outbound: &Outbound,
+    target: Address,
+) -> io::Result<()> {
+    let mut target = match outbound.connect(&target.into()).await {
+        Ok(target) => target,
+        Err(e) => {
+            reply(stream, R::failed(&e)).await?;
+            return Err(e);
+        }
+    };
+    let target_buffer_size = target.buffer_size();
+    reply(stream, R::succeeded(target.local_addr()?)).await?;
+
+    match copy_bidirectional(stream, &mut target, 1 << 14, target_buffer_size).await {
+        Ok(_) => {
+            debug!("TCP connection closed");
+        }
+        Err(e) => {
+            debug!("TCP connection closed with error: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// accept one connection from the expected peer for the client, then relay it
+pub(crate) async fn bind<R: ReplyEncoder>(
+    stream: &mut TcpStream,
+    outbound: &Outbound,
+    expected_peer: Address,
+) -> io::Result<()> {
+    // the peer connects to this host, which is only right without a proxy
+    if !matches!(outbound, Outbound::Direct) {
+        reply(stream, R::not_supported()).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "BIND is only supported by the direct outbound",
+        ));
+    }
+
+    // 1. the address the peer should connect to
+    let binding = match Binding::bind(stream.local_addr()?.ip(), expected_peer).await {
+        Ok(binding) => binding,
+        Err(e) => {
+            reply(stream, R::failed(&e)).await?;
+            return Err(e);
+        }
+    };
+    let bind_addr = binding.local_addr()?;
+    reply(stream, R::succeeded(bind_addr)).await?;
+    debug!("Wait for the peer on {}", bind_addr);
+
+    // 2. the address of the peer once it is connected
+    let (mut inbound, peer) = match binding.accept().await {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            reply(stream, R::failed(&e)).await?;
+            return Err(e);
+        }
+    };
+    info!("Peer {} connected to {}", peer, bind_addr);
+    reply(stream, R::succeeded(peer)).await?;
+
+    match copy_bidirectional(stream, &mut inbound, 1 << 14, 1 << 14).await {
+        Ok(_) => {
+            debug!("TCP connection closed");
+        }
+        Err(e) => {
+            debug!("TCP connection closed with error: {}", e);
+        }
+    }
+
+    Ok(())
+}
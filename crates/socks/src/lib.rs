@@ -1,9 +1,14 @@
 #![feature(allocator_api)]
 mod auth;
+mod bind;
+mod command;
 mod relay;
 mod server;
+mod socks4;
 mod socks5;
+mod udp;
 
+pub use auth::{Authentication, Users};
 pub use server::SocksServer;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -1,39 +1,77 @@
+use common::outbound::{Outbound, OutboundRegistry};
 use log::{error, info, warn};
 use std::{
     fmt::{Display, Formatter},
     io::{self, ErrorKind, Result},
     net::SocketAddr,
+    sync::Arc,
 };
 use tokio::net::{TcpListener, TcpStream};
 
-use crate::socks5::Socks5TcpHandler;
+use crate::{auth::Authentication, socks4::Socks4TcpHandler, socks5::Socks5TcpHandler};
 
 pub struct SocksServer {
     pub listener: TcpListener,
+    /// all outbounds, keyed by tag
+    outbounds: Arc<OutboundRegistry>,
+    /// tag of the outbound used by this server
+    outbound: String,
+    /// authentication required from the clients
+    auth: Authentication,
 }
 
 impl SocksServer {
-    pub async fn new(addr: &str) -> Result<Self> {
-        info!("Starting socks server on {}", addr);
+    pub async fn new(addr: &str, outbounds: Arc<OutboundRegistry>, outbound: &str) -> Result<Self> {
+        if !outbounds.contains(outbound) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("Unknown outbound: {}", outbound),
+            ));
+        }
+        info!("Starting socks server on {}, outbound: {}", addr, outbound);
         Ok(Self {
             listener: TcpListener::bind(addr).await?,
+            outbounds,
+            outbound: outbound.to_string(),
+            auth: Authentication::NoAuth,
         })
     }
 
+    /// require auth from the clients instead of no authentication
+    pub fn with_auth(mut self, auth: Authentication) -> Self {
+        self.auth = auth;
+        self
+    }
+
     pub async fn serve(&mut self) -> Result<()> {
         info!("Serving socks server");
         loop {
             let (stream, peer_addr) = self.listener.accept().await?;
             info!("Accepted connection from {}", peer_addr);
+            let outbound = match self.outbounds.get(&self.outbound) {
+                Some(outbound) => outbound.clone(),
+                None => {
+                    error!("Unknown outbound: {}", self.outbound);
+                    continue;
+                }
+            };
+            let auth = self.auth.clone();
             tokio::spawn(async move {
-                if let Err(e) = SocksServer::handle_tcp_client(stream, peer_addr).await {
+                if let Err(e) =
+                    SocksServer::handle_tcp_client(stream, peer_addr, outbound, auth).await
+                {
                     error!("Error handling client: {}", e);
                 }
             });
         }
     }
 
-    pub async fn handle_tcp_client(stream: TcpStream, peer: SocketAddr) -> io::Result<()> {
+    pub async fn handle_tcp_client(
+        stream: TcpStream,
+        peer: SocketAddr,
+        outbound: Outbound,
+        auth: Authentication,
+    ) -> io::Result<()> {
         let mut version_buf = [0u8; 1];
         let n = stream.peek(&mut version_buf).await?;
         if n == 0 {
@@ -42,14 +80,11 @@ impl SocksServer {
 
         match version_buf[0] {
             0x04 => {
-                warn!("Socks4 is not supported");
-                Err(io::Error::new(
-                    ErrorKind::InvalidData,
-                    "Socks4 is not supported",
-                ))
+                let mut handler = Socks4TcpHandler::new(outbound, auth);
+                handler.handle_socks4_client(stream, peer).await
             }
             0x05 => {
-                let mut handler = Socks5TcpHandler::new();
+                let mut handler = Socks5TcpHandler::new(outbound, auth);
                 handler.handle_socks5_client(stream, peer).await
             }
             version => {
@@ -0,0 +1,352 @@
+use std::{
+    fmt::Display,
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+};
+
+use common::outbound::Outbound;
+use log::{debug, trace};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    auth::Authentication,
+    command::{self, ReplyEncoder},
+    socks5::Address,
+    SocksCommand, Version,
+};
+
+/// max length of the user id and of the host name of SOCKS4a
+const MAX_FIELD_SIZE: usize = 255;
+
+pub struct Socks4TcpHandler {
+    outbound: Outbound,
+    auth: Authentication,
+}
+
+impl Socks4TcpHandler {
+    pub fn new(outbound: Outbound, auth: Authentication) -> Self {
+        Self { outbound, auth }
+    }
+
+    pub async fn handle_socks4_client(
+        &mut self,
+        mut stream: TcpStream,
+        peer_addr: SocketAddr,
+    ) -> io::Result<()> {
+        let request = match Socks4Request::from_stream(&mut stream).await {
+            Ok(request) => request,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                reject(&mut stream).await?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        trace!("Request: {:?}", request);
+        debug!(
+            "{} (user id {:?}) requests {}",
+            peer_addr, request.user_id, request
+        );
+
+        // SOCKS4 has no password, the user id is not authenticated
+        if !matches!(self.auth, Authentication::NoAuth) {
+            reject(&mut stream).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SOCKS4 is not allowed by an inbound requiring authentication",
+            ));
+        }
+
+        match request.command {
+            SocksCommand::Connect => self.handle_tcp_connect(&mut stream, request.address).await,
+            SocksCommand::Bind => self.handle_tcp_bind(&mut stream, request.address).await,
+            SocksCommand::UdpAssociate => unreachable!("SOCKS4 has no UDP ASSOCIATE"),
+        }
+    }
+
+    pub async fn handle_tcp_connect(
+        &mut self,
+        stream: &mut TcpStream,
+        target: Address,
+    ) -> io::Result<()> {
+        command::connect::<Socks4Response>(stream, &self.outbound, target).await
+    }
+
+    /// accept one connection from the expected peer for the client, then relay it
+    pub async fn handle_tcp_bind(
+        &mut self,
+        stream: &mut TcpStream,
+        expected_peer: Address,
+    ) -> io::Result<()> {
+        command::bind::<Socks4Response>(stream, &self.outbound, expected_peer).await
+    }
+}
+
+/// send the rejected reply
+async fn reject(stream: &mut TcpStream) -> io::Result<()> {
+    stream.write_all(&Socks4Response::rejected().encode()).await
+}
+
+/// SOCKS4 request, the target is a host name in SOCKS4a
+#[derive(Debug, Clone)]
+pub struct Socks4Request {
+    command: SocksCommand,
+    address: Address,
+    user_id: String,
+}
+
+impl Display for Socks4Request {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let command = match self.command {
+            SocksCommand::Connect => "CONNECT",
+            SocksCommand::Bind => "BIND",
+            SocksCommand::UdpAssociate => "UDP_ASSOCIATE",
+        };
+        write!(f, "{} {}", command, self.address)
+    }
+}
+
+impl Socks4Request {
+    pub async fn from_stream(stream: &mut TcpStream) -> io::Result<Socks4Request> {
+        let mut req_buf = [0u8; 8];
+        stream.read_exact(&mut req_buf).await?;
+
+        if req_buf[0] != Version::Socks4 as u8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid version: {}", req_buf[0]),
+            ));
+        }
+        let port = u16::from_be_bytes([req_buf[2], req_buf[3]]);
+        let ip = Ipv4Addr::new(req_buf[4], req_buf[5], req_buf[6], req_buf[7]);
+
+        let user_id = read_null_terminated(stream).await?;
+
+        // SOCKS4a: 0.0.0.x with x not 0, the host name follows the user id
+        let octets = ip.octets();
+        let address = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+            Address::DomainName(read_null_terminated(stream).await?, port)
+        } else {
+            Address::SocketAddr(SocketAddr::V4(SocketAddrV4::new(ip, port)))
+        };
+
+        // checked once the whole request is read, so that the rejection is not lost
+        let command = match req_buf[1] {
+            0x01 => SocksCommand::Connect,
+            0x02 => SocksCommand::Bind,
+            command => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid command: {}", command),
+                ))
+            }
+        };
+
+        Ok(Socks4Request {
+            command,
+            address,
+            user_id,
+        })
+    }
+}
+
+/// read a string terminated by a null byte
+async fn read_null_terminated(stream: &mut TcpStream) -> io::Result<String> {
+    let mut buf = Vec::new();
+    loop {
+        let b = stream.read_u8().await?;
+        if b == 0 {
+            break;
+        }
+        if buf.len() == MAX_FIELD_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "field is too long",
+            ));
+        }
+        buf.push(b);
+    }
+    String::from_utf8(buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid string: {}", e)))
+}
+
+/// SOCKS4 reply, the version is 0
+#[derive(Debug, Clone, Copy)]
+struct Socks4Response {
+    granted: bool,
+    addr: SocketAddrV4,
+}
+
+impl ReplyEncoder for Socks4Response {
+    /// request granted (0x5A), addr is only sent if it is an ipv4 address
+    fn succeeded(addr: SocketAddr) -> Self {
+        let addr = match addr {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(_) => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+        };
+        Self {
+            granted: true,
+            addr,
+        }
+    }
+
+    /// SOCKS4 only has one failure reply
+    fn failed(_: &io::Error) -> Self {
+        Self::rejected()
+    }
+
+    fn not_supported() -> Self {
+        Self::rejected()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; 8];
+        buf[1] = if self.granted { 0x5A } else { 0x5B };
+        buf[2..4].copy_from_slice(&self.addr.port().to_be_bytes());
+        buf[4..].copy_from_slice(&self.addr.ip().octets());
+        buf
+    }
+}
+
+impl Socks4Response {
+    /// request rejected or failed (0x5B)
+    fn rejected() -> Self {
+        Self {
+            granted: false,
+            addr: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::{net::TcpListener, time::timeout};
+
+    use super::*;
+    use crate::Users;
+
+    /// a local connection, the client end and the server end
+    async fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    /// read request as the server
+    async fn read_request(request: &[u8]) -> io::Result<Socks4Request> {
+        let (mut client, mut server) = connection().await;
+        client.write_all(request).await.unwrap();
+        Socks4Request::from_stream(&mut server).await
+    }
+
+    /// request header of command to ip:port
+    fn header(command: u8, ip: [u8; 4], port: u16) -> Vec<u8> {
+        let mut request = vec![0x04, command];
+        request.extend_from_slice(&port.to_be_bytes());
+        request.extend_from_slice(&ip);
+        request
+    }
+
+    #[tokio::test]
+    async fn socks4_request() {
+        let mut request = header(0x01, [192, 0, 2, 1], 80);
+        request.extend_from_slice(b"fred\0");
+        let request = read_request(&request).await.unwrap();
+        assert!(matches!(request.command, SocksCommand::Connect));
+        assert_eq!(
+            request.address,
+            Address::SocketAddr(([192, 0, 2, 1], 80).into())
+        );
+        assert_eq!(request.user_id, "fred");
+
+        // 0.0.0.0 is not the SOCKS4a form, nothing follows the user id
+        let mut request = header(0x02, [0, 0, 0, 0], 21);
+        request.push(0);
+        let request = read_request(&request).await.unwrap();
+        assert!(matches!(request.command, SocksCommand::Bind));
+        assert_eq!(
+            request.address,
+            Address::SocketAddr(([0, 0, 0, 0], 21).into())
+        );
+    }
+
+    #[tokio::test]
+    async fn socks4a_request() {
+        // the host name follows the user id, empty or not
+        for user_id in ["", "fred", &"u".repeat(MAX_FIELD_SIZE)] {
+            let mut request = header(0x01, [0, 0, 0, 1], 443);
+            request.extend_from_slice(user_id.as_bytes());
+            request.push(0);
+            request.extend_from_slice(b"example.com\0");
+            let request = read_request(&request).await.unwrap();
+            assert_eq!(
+                request.address,
+                Address::DomainName("example.com".to_string(), 443)
+            );
+            assert_eq!(request.user_id, user_id);
+        }
+    }
+
+    #[tokio::test]
+    async fn field_too_long() {
+        // a user id, then a host name after an empty user id, longer than the limit
+        let mut long_user_id = header(0x01, [0, 0, 0, 1], 443);
+        long_user_id.extend_from_slice(&[b'u'; 1000]);
+        let mut long_host = header(0x01, [0, 0, 0, 1], 443);
+        long_host.push(0);
+        long_host.extend_from_slice(&[b'h'; 1000]);
+
+        for request in [long_user_id, long_host] {
+            // the client keeps the connection open without ending the field
+            let (mut client, mut server) = connection().await;
+            client.write_all(&request).await.unwrap();
+            let e = timeout(
+                Duration::from_secs(1),
+                Socks4Request::from_stream(&mut server),
+            )
+            .await
+            .expect("the field is not read forever")
+            .unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    /// run a handler for request, return its result and the reply
+    async fn handle(auth: Authentication, request: &[u8]) -> (io::Result<()>, Vec<u8>) {
+        let (mut client, server) = connection().await;
+        client.write_all(request).await.unwrap();
+        let peer = client.local_addr().unwrap();
+        let mut handler = Socks4TcpHandler::new(Outbound::Direct, auth);
+        let result = handler.handle_socks4_client(server, peer).await;
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        (result, reply)
+    }
+
+    #[tokio::test]
+    async fn unknown_command_is_rejected() {
+        let mut request = header(0x03, [192, 0, 2, 1], 80);
+        request.push(0);
+        let (result, reply) = handle(Authentication::NoAuth, &request).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(reply, [0x00, 0x5B, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn rejected_when_auth_is_required() {
+        let mut users = Users::new();
+        users.insert("fred", "secret");
+        let mut request = header(0x01, [127, 0, 0, 1], 9);
+        request.extend_from_slice(b"fred\0");
+        let (result, reply) = handle(Authentication::UserPass(Arc::new(users)), &request).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(reply, [0x00, 0x5B, 0, 0, 0, 0, 0, 0]);
+    }
+}
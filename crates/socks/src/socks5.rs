@@ -4,26 +4,43 @@ use std::{
     net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
 };
 
-use common::proxy::ProxyClientStream;
-use log::{debug, trace};
+use common::{net::ServerAddr, outbound::Outbound};
+use log::{debug, info, trace};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
 };
-use vmess::stream::VMESSStream;
 
 use crate::{
-    auth::{AuthMethod, HandshakeResponse},
-    relay::copy_bidirectional,
+    auth::{
+        write_user_pass_status, AuthMethod, Authentication, HandshakeResponse, UserPassRequest,
+        UserPassStatus,
+    },
+    command::{self, ReplyEncoder},
     server::Reply,
+    udp::UdpAssociation,
     AddressType, Version,
 };
 
-pub struct Socks5TcpHandler {}
+pub struct Socks5TcpHandler {
+    outbound: Outbound,
+    auth: Authentication,
+    /// name of the authenticated user
+    user: Option<String>,
+}
 
 impl Socks5TcpHandler {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(outbound: Outbound, auth: Authentication) -> Self {
+        Self {
+            outbound,
+            auth,
+            user: None,
+        }
+    }
+
+    /// name of the user authenticated by username/password, None before or without it
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
     }
 
     pub async fn handle_socks5_client(
@@ -40,12 +57,21 @@ impl Socks5TcpHandler {
 
         // 2. auth
         self.handle_auth(&mut stream, &handshake_request).await?;
+        if let Some(user) = &self.user {
+            info!("{} authenticated as {}", peer_addr, user);
+        }
 
         // here we have the request
         // 3. request
         let header = TcpRequestHeader::from_stream(&mut stream).await?;
 
         trace!("Request header: {:?}", header);
+        debug!(
+            "{} (user {}) requests {}",
+            peer_addr,
+            self.user().unwrap_or("-"),
+            header
+        );
 
         // respond to the client
         match header.command {
@@ -53,10 +79,11 @@ impl Socks5TcpHandler {
                 self.handle_tcp_connect(&mut stream, header.address).await?;
             }
             Command::Bind => {
-                todo!()
+                self.handle_tcp_bind(&mut stream, header.address).await?;
             }
             Command::UdpAssociate => {
-                todo!()
+                self.handle_udp_associate(&mut stream, peer_addr, header.address)
+                    .await?;
             }
         }
 
@@ -68,30 +95,44 @@ impl Socks5TcpHandler {
         stream: &mut TcpStream,
         target: Address,
     ) -> io::Result<()> {
-        let outbound = "vmess";
+        command::connect::<TcpResponseHeader>(stream, &self.outbound, target).await
+    }
 
-        let mut target = match outbound {
-            "DIRECT" => ProxyClientStream::DIRECT(TcpStream::connect(target.to_string()).await?),
-            "vmess" => ProxyClientStream::VMESS(VMESSStream::connect("127.0.0.1:1081").await?),
-            _ => {
-                todo!()
-            }
-        };
-        let target_buffer_size = target.buffer_size();
-        let response =
-            TcpResponseHeader::new(Reply::Succeeded, Address::SocketAddr(target.local_addr()?));
-        stream.write_all(&response.to_bytes()).await?;
+    /// accept one connection from the expected peer for the client, then relay it
+    pub async fn handle_tcp_bind(
+        &mut self,
+        stream: &mut TcpStream,
+        expected_peer: Address,
+    ) -> io::Result<()> {
+        command::bind::<TcpResponseHeader>(stream, &self.outbound, expected_peer).await
+    }
 
-        match copy_bidirectional(stream, &mut target, 1 << 14, target_buffer_size).await {
-            Ok(_) => {
-                debug!("TCP connection closed");
-            }
-            Err(e) => {
-                debug!("TCP connection closed with error: {}", e);
-            }
-        }
+    /// relay the datagrams of the client until it closes stream
+    pub async fn handle_udp_associate(
+        &mut self,
+        stream: &mut TcpStream,
+        peer_addr: SocketAddr,
+        client: Address,
+    ) -> io::Result<()> {
+        let local_ip = stream.local_addr()?.ip();
+        let association =
+            match UdpAssociation::bind(local_ip, peer_addr.ip(), &client, self.outbound.clone())
+                .await
+            {
+                Ok(association) => association,
+                Err(e) => {
+                    let response =
+                        TcpResponseHeader::new(Reply::GeneralFailure, unspecified_address());
+                    stream.write_all(&response.to_bytes()).await?;
+                    return Err(e);
+                }
+            };
+        let relay_addr = association.local_addr()?;
+        let response = TcpResponseHeader::new(Reply::Succeeded, Address::SocketAddr(relay_addr));
+        stream.write_all(&response.to_bytes()).await?;
+        debug!("Relay udp of {} on {}", peer_addr, relay_addr);
 
-        Ok(())
+        association.run(stream).await
     }
 
     pub async fn handle_auth(
@@ -100,16 +141,52 @@ impl Socks5TcpHandler {
         handshake_request: &HandshakeRequest,
     ) -> io::Result<()> {
         debug!("Handling auth");
+        // the first method allowed by the inbound which is offered by the client
+        let method = self
+            .auth
+            .methods()
+            .iter()
+            .copied()
+            .find(|&method| handshake_request.methods.contains(&(method as u8)));
         let handshake_response = HandshakeResponse {
             version: Version::Socks5,
-            method: AuthMethod::NoAuth,
+            method: method.unwrap_or(AuthMethod::NoAcceptable),
         };
         trace!("Handshake response: {}", handshake_response);
         stream.write_all(&handshake_response.to_bytes()).await?;
+        if method.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "no acceptable auth method, client offers {:?}, inbound allows {:?}",
+                    handshake_request.methods,
+                    self.auth.methods()
+                ),
+            ));
+        }
+
+        if let Authentication::UserPass(users) = &self.auth {
+            let request = UserPassRequest::read_from(stream).await?;
+            trace!("Username/password request: {:?}", request);
+            if !users.check(&request.username, &request.password) {
+                write_user_pass_status(stream, UserPassStatus::Failed).await?;
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("authentication failed for user {}", request.username),
+                ));
+            }
+            write_user_pass_status(stream, UserPassStatus::Succeeded).await?;
+            self.user = Some(request.username);
+        }
         Ok(())
     }
 }
 
+/// address of the failure replies
+fn unspecified_address() -> Address {
+    Address::SocketAddr(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))
+}
+
 /// client handshake request
 #[derive(Debug, Clone)]
 pub struct HandshakeRequest {
@@ -172,14 +249,61 @@ impl From<u8> for Command {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Address {
     SocketAddr(SocketAddr),
     DomainName(String, u16),
 }
 
 impl Address {
-    fn to_bytes(&self) -> Vec<u8> {
+    /// read an address written by `to_bytes` from the front of buf,
+    /// return the address and the number of bytes read
+    pub(crate) fn from_bytes(buf: &[u8]) -> io::Result<(Address, usize)> {
+        const IPV4: u8 = AddressType::Ipv4 as u8;
+        const IPV6: u8 = AddressType::Ipv6 as u8;
+        const DOMAIN_NAME: u8 = AddressType::DomainName as u8;
+
+        let truncated = || io::Error::new(io::ErrorKind::UnexpectedEof, "address is truncated");
+        let (&address_type, rest) = buf.split_first().ok_or_else(truncated)?;
+        let len = match address_type {
+            IPV4 => 4,
+            IPV6 => 16,
+            // domain name is prefixed by its length
+            DOMAIN_NAME => 1 + *rest.first().ok_or_else(truncated)? as usize,
+            t => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid address type: {}", t),
+                ))
+            }
+        };
+        let addr = rest.get(..len).ok_or_else(truncated)?;
+        let port = rest.get(len..len + 2).ok_or_else(truncated)?;
+        let port = u16::from_be_bytes([port[0], port[1]]);
+
+        let address = match address_type {
+            IPV4 => {
+                let ip: [u8; 4] = addr.try_into().expect("length is 4");
+                Address::SocketAddr(SocketAddr::new(ip.into(), port))
+            }
+            IPV6 => {
+                let ip: [u8; 16] = addr.try_into().expect("length is 16");
+                Address::SocketAddr(SocketAddr::new(ip.into(), port))
+            }
+            _ => {
+                let domain = String::from_utf8(addr[1..].to_vec()).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid domain name: {}", e),
+                    )
+                })?;
+                Address::DomainName(domain, port)
+            }
+        };
+        Ok((address, 1 + len + 2))
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
         let mut buf = Vec::new();
         match self {
             Address::SocketAddr(addr) => match addr {
@@ -205,6 +329,15 @@ impl Address {
     }
 }
 
+impl From<Address> for ServerAddr {
+    fn from(address: Address) -> Self {
+        match address {
+            Address::SocketAddr(addr) => ServerAddr::SocketAddr(addr),
+            Address::DomainName(domain, port) => ServerAddr::DomainName(domain, port),
+        }
+    }
+}
+
 impl Display for Address {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -310,8 +443,290 @@ impl TcpResponseHeader {
     }
 }
 
+impl ReplyEncoder for TcpResponseHeader {
+    fn succeeded(addr: SocketAddr) -> Self {
+        Self::new(Reply::Succeeded, Address::SocketAddr(addr))
+    }
+
+    fn failed(error: &io::Error) -> Self {
+        let reply = match error.kind() {
+            io::ErrorKind::TimedOut => Reply::TTLExpired,
+            io::ErrorKind::PermissionDenied => Reply::ConnectionNotAllowed,
+            io::ErrorKind::ConnectionRefused => Reply::ConnectionRefused,
+            io::ErrorKind::HostUnreachable => Reply::HostUnreachable,
+            io::ErrorKind::NetworkUnreachable => Reply::NetworkUnreachable,
+            _ => Reply::GeneralFailure,
+        };
+        Self::new(reply, unspecified_address())
+    }
+
+    fn not_supported() -> Self {
+        Self::new(Reply::CommandNotSupported, unspecified_address())
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        self.to_bytes()
+    }
+}
+
 impl Display for TcpResponseHeader {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{} {}", self.reply, self.address)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::{net::TcpListener, task::JoinHandle, time::timeout};
+    use uuid::Uuid;
+    use vmess::VmessUser;
+
+    use super::*;
+    use crate::Users;
+
+    fn user_pass() -> Authentication {
+        let mut users = Users::new();
+        users.insert("alice", "hunter2");
+        Authentication::UserPass(Arc::new(users))
+    }
+
+    /// run a handler requiring auth for a local client, return the client and the handler
+    async fn start(
+        auth: Authentication,
+    ) -> (TcpStream, JoinHandle<(io::Result<()>, Socks5TcpHandler)>) {
+        start_with(Outbound::Direct, auth).await
+    }
+
+    async fn start_with(
+        outbound: Outbound,
+        auth: Authentication,
+    ) -> (TcpStream, JoinHandle<(io::Result<()>, Socks5TcpHandler)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, peer) = listener.accept().await.unwrap();
+        let handler = tokio::spawn(async move {
+            let mut handler = Socks5TcpHandler::new(outbound, auth);
+            let result = handler.handle_socks5_client(stream, peer).await;
+            (result, handler)
+        });
+        (client, handler)
+    }
+
+    /// read the replies of the server until it closes the connection
+    async fn read_until_closed(client: &mut TcpStream) -> Vec<u8> {
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        replies
+    }
+
+    /// username/password sub-negotiation of username and password
+    fn user_pass_request(username: &str, password: &str) -> Vec<u8> {
+        let mut request = vec![0x01, username.len() as u8];
+        request.extend_from_slice(username.as_bytes());
+        request.push(password.len() as u8);
+        request.extend_from_slice(password.as_bytes());
+        request
+    }
+
+    #[tokio::test]
+    async fn user_pass_succeeds() {
+        let (mut client, handler) = start(user_pass()).await;
+        client.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+        client
+            .write_all(&user_pass_request("alice", "hunter2"))
+            .await
+            .unwrap();
+        let mut replies = [0u8; 4];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies, [0x05, 0x02, 0x01, 0x00]);
+
+        // the client leaves before its request
+        drop(client);
+        let (result, handler) = handler.await.unwrap();
+        assert!(result.is_err());
+        assert_eq!(handler.user(), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn user_pass_fails() {
+        for (username, password) in [("alice", "hunter3"), ("bob", "hunter2"), ("alice", "")] {
+            let (mut client, handler) = start(user_pass()).await;
+            client.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+            client
+                .write_all(&user_pass_request(username, password))
+                .await
+                .unwrap();
+            // failure status, then the connection is closed
+            assert_eq!(
+                read_until_closed(&mut client).await,
+                [0x05, 0x02, 0x01, 0x01]
+            );
+            let (result, handler) = handler.await.unwrap();
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+            assert_eq!(handler.user(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn user_pass_wrong_version() {
+        let (mut client, handler) = start(user_pass()).await;
+        client.write_all(&[0x05, 0x01, 0x02]).await.unwrap();
+        // the version of the socks request instead of the sub-negotiation
+        client.write_all(&[0x05]).await.unwrap();
+        assert_eq!(read_until_closed(&mut client).await, [0x05, 0x02]);
+        let (result, handler) = handler.await.unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(handler.user(), None);
+    }
+
+    #[tokio::test]
+    async fn no_acceptable_method() {
+        // only NoAuth offered to a password inbound, only UserPass to an open one
+        for (auth, method) in [(user_pass(), 0x00), (Authentication::NoAuth, 0x02)] {
+            let (mut client, handler) = start(auth).await;
+            client.write_all(&[0x05, 0x01, method]).await.unwrap();
+            assert_eq!(read_until_closed(&mut client).await, [0x05, 0xFF]);
+            let (result, _) = handler.await.unwrap();
+            assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        }
+    }
+
+    #[tokio::test]
+    async fn method_offered_by_both() {
+        // UserPass is selected whatever the order of the offered methods
+        for methods in [[0x00, 0x02], [0x02, 0x00]] {
+            let (mut client, handler) = start(user_pass()).await;
+            client.write_all(&[0x05, 0x02]).await.unwrap();
+            client.write_all(&methods).await.unwrap();
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [0x05, 0x02]);
+            client
+                .write_all(&user_pass_request("alice", "hunter2"))
+                .await
+                .unwrap();
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [0x01, 0x00]);
+            drop(client);
+            let (_, handler) = handler.await.unwrap();
+            assert_eq!(handler.user(), Some("alice"));
+        }
+
+        // NoAuth among other methods, nothing more is negotiated
+        let (mut client, handler) = start(Authentication::NoAuth).await;
+        client
+            .write_all(&[0x05, 0x03, 0x01, 0x02, 0x00])
+            .await
+            .unwrap();
+        // no request follows, the handler stops after the reply
+        client.shutdown().await.unwrap();
+        assert_eq!(read_until_closed(&mut client).await, [0x05, 0x00]);
+        let (_, handler) = handler.await.unwrap();
+        assert_eq!(handler.user(), None);
+    }
+
+    /// request a BIND for expected_peer without auth
+    async fn bind_request(client: &mut TcpStream, expected_peer: SocketAddr) {
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x00]);
+        let mut request = vec![0x05, 0x02, 0x00];
+        request.extend_from_slice(&Address::SocketAddr(expected_peer).to_bytes());
+        client.write_all(&request).await.unwrap();
+    }
+
+    /// read a reply with an ipv4 address, return the reply code and the address
+    async fn read_bind_reply(client: &mut TcpStream) -> (u8, SocketAddr) {
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..4], [0x05, reply[1], 0x00, 0x01]);
+        let ip: [u8; 4] = reply[4..8].try_into().unwrap();
+        let port = u16::from_be_bytes([reply[8], reply[9]]);
+        (reply[1], (ip, port).into())
+    }
+
+    #[tokio::test]
+    async fn bind_relays_the_peer() {
+        let (mut client, handler) = start(Authentication::NoAuth).await;
+        bind_request(&mut client, ([127, 0, 0, 1], 0).into()).await;
+
+        // 1. the address the peer connects to, on the ip of the control connection
+        let (reply, bind_addr) = read_bind_reply(&mut client).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        assert_eq!(bind_addr.ip(), client.peer_addr().unwrap().ip());
+        assert_ne!(bind_addr.port(), 0);
+
+        // 2. the address of the peer
+        let mut peer = TcpStream::connect(bind_addr).await.unwrap();
+        let (reply, peer_addr) = read_bind_reply(&mut client).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        assert_eq!(peer_addr, peer.local_addr().unwrap());
+
+        peer.write_all(b"from the peer").await.unwrap();
+        let mut buf = [0u8; 13];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"from the peer");
+        client.write_all(b"from the client").await.unwrap();
+        let mut buf = [0u8; 15];
+        peer.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"from the client");
+
+        drop(peer);
+        drop(client);
+        handler.await.unwrap().0.unwrap();
+    }
+
+    #[tokio::test]
+    async fn bind_refuses_another_peer() {
+        let (mut client, handler) = start(Authentication::NoAuth).await;
+        bind_request(&mut client, ([192, 0, 2, 1], 21).into()).await;
+        let (reply, bind_addr) = read_bind_reply(&mut client).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+
+        let _peer = TcpStream::connect(bind_addr).await.unwrap();
+        let (reply, _) = read_bind_reply(&mut client).await;
+        assert_eq!(reply, Reply::ConnectionNotAllowed as u8);
+        assert!(read_until_closed(&mut client).await.is_empty());
+        let (result, _) = handler.await.unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bind_times_out() {
+        let (mut client, handler) = start(Authentication::NoAuth).await;
+        bind_request(&mut client, ([127, 0, 0, 1], 0).into()).await;
+        let (reply, _) = read_bind_reply(&mut client).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+
+        // nobody connects, the paused clock jumps to the timeout
+        let start = tokio::time::Instant::now();
+        let (reply, _) = read_bind_reply(&mut client).await;
+        assert_eq!(reply, Reply::TTLExpired as u8);
+        assert!(start.elapsed() >= Duration::from_secs(60));
+        assert!(read_until_closed(&mut client).await.is_empty());
+        let (result, _) = handler.await.unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test]
+    async fn bind_needs_the_direct_outbound() {
+        let outbound = Outbound::Vmess {
+            addr: ServerAddr::new("127.0.0.1", 9),
+            user: VmessUser::new(Uuid::from_u128(1)),
+        };
+        let (mut client, handler) = start_with(outbound, Authentication::NoAuth).await;
+        bind_request(&mut client, ([127, 0, 0, 1], 0).into()).await;
+        let (reply, _) = timeout(Duration::from_secs(1), read_bind_reply(&mut client))
+            .await
+            .expect("the failure is replied at once");
+        assert_eq!(reply, Reply::CommandNotSupported as u8);
+        assert!(read_until_closed(&mut client).await.is_empty());
+        let (result, _) = handler.await.unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+}
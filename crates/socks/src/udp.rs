@@ -0,0 +1,331 @@
+//! UDP ASSOCIATE
+//!
+//! The client sends its datagrams, prefixed by the UDP request header, to the relay socket.
+//! Each target gets its own session through the outbound, kept in a NAT table until the
+//! controlling TCP connection is closed.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use common::{
+    outbound::Outbound,
+    proxy::{ProxyUdpRecvHalf, ProxyUdpSendHalf},
+};
+use log::{debug, trace};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpStream, UdpSocket},
+    sync::mpsc::{self, error::TrySendError},
+};
+
+use crate::socks5::Address;
+
+/// max size of the data of a datagram
+const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
+
+/// datagrams queued for a target, newer ones are dropped when the queue is full
+const NAT_QUEUE_SIZE: usize = 64;
+
+/// size of RSV and FRAG, before the address in the UDP request header
+const HEADER_PREFIX_SIZE: usize = 3;
+
+/// Relay of one UDP ASSOCIATE request
+pub(crate) struct UdpAssociation {
+    socket: Arc<UdpSocket>,
+    outbound: Outbound,
+    /// ip of the controlling connection, datagrams from other hosts are dropped
+    client_ip: IpAddr,
+    /// port the client announced in the request, 0 if unknown
+    client_port: u16,
+    /// address the client sends from, set by its first datagram
+    client: Option<SocketAddr>,
+    /// queue of the datagrams to each target
+    nat: HashMap<Address, mpsc::Sender<Vec<u8>>>,
+}
+
+impl UdpAssociation {
+    /// bind the relay socket on local_ip for the client at client_ip,
+    /// request is the address the client announced in its request
+    pub(crate) async fn bind(
+        local_ip: IpAddr,
+        client_ip: IpAddr,
+        request: &Address,
+        outbound: Outbound,
+    ) -> io::Result<Self> {
+        let client_port = match request {
+            Address::SocketAddr(addr) => addr.port(),
+            Address::DomainName(_, port) => *port,
+        };
+        Ok(Self {
+            socket: Arc::new(UdpSocket::bind((local_ip, 0)).await?),
+            outbound,
+            client_ip,
+            client_port,
+            client: None,
+            nat: HashMap::new(),
+        })
+    }
+
+    /// address of the relay socket, where the client sends its datagrams
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// relay the datagrams until control, the connection of the request, is closed
+    pub(crate) async fn run(mut self, control: &mut TcpStream) -> io::Result<()> {
+        let result = tokio::select! {
+            result = self.relay() => result,
+            result = wait_closed(control) => result,
+        };
+        debug!(
+            "Udp association of {:?} closed, {} targets",
+            self.client,
+            self.nat.len()
+        );
+        // dropping the queues ends the sessions
+        result
+    }
+
+    async fn relay(&mut self) -> io::Result<()> {
+        let mut buf = vec![0u8; HEADER_PREFIX_SIZE + 1 + 256 + 2 + MAX_DATAGRAM_SIZE];
+        loop {
+            let (n, from) = self.socket.recv_from(&mut buf).await?;
+            if !self.accepts(from) {
+                trace!("Dropped datagram from unknown client {}", from);
+                continue;
+            }
+            match parse_datagram(&buf[..n]) {
+                Ok((target, data)) => self.forward(target, data.to_vec(), from),
+                Err(e) => debug!("Dropped datagram from {}: {}", from, e),
+            }
+        }
+    }
+
+    /// whether the datagram from comes from the client
+    fn accepts(&mut self, from: SocketAddr) -> bool {
+        match self.client {
+            Some(client) => client == from,
+            None => {
+                if from.ip() != self.client_ip
+                    || (self.client_port != 0 && from.port() != self.client_port)
+                {
+                    return false;
+                }
+                self.client = Some(from);
+                true
+            }
+        }
+    }
+
+    /// queue the datagram to the session of target, open the session if there is none
+    fn forward(&mut self, target: Address, datagram: Vec<u8>, client: SocketAddr) {
+        let datagram = match self.nat.get(&target) {
+            Some(queue) => match queue.try_send(datagram) {
+                Ok(()) => return,
+                Err(TrySendError::Full(_)) => {
+                    debug!("Queue to {} is full, dropped a datagram", target);
+                    return;
+                }
+                // the session is closed, open a new one
+                Err(TrySendError::Closed(datagram)) => datagram,
+            },
+            None => datagram,
+        };
+
+        debug!("Open udp session from {} to {}", client, target);
+        let (queue, datagrams) = mpsc::channel(NAT_QUEUE_SIZE);
+        queue.try_send(datagram).expect("queue is empty");
+        self.nat.insert(target.clone(), queue);
+        tokio::spawn(nat_session(
+            self.outbound.clone(),
+            target,
+            datagrams,
+            self.socket.clone(),
+            client,
+        ));
+    }
+}
+
+/// split a datagram of the client into its target and data
+fn parse_datagram(buf: &[u8]) -> io::Result<(Address, &[u8])> {
+    if buf.len() < HEADER_PREFIX_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "header is truncated",
+        ));
+    }
+    if buf[2] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("fragment {} is not supported", buf[2]),
+        ));
+    }
+    let (target, len) = Address::from_bytes(&buf[HEADER_PREFIX_SIZE..])?;
+    Ok((target, &buf[HEADER_PREFIX_SIZE + len..]))
+}
+
+/// relay the datagrams between the client and target, until the queue is dropped
+async fn nat_session(
+    outbound: Outbound,
+    target: Address,
+    mut datagrams: mpsc::Receiver<Vec<u8>>,
+    socket: Arc<UdpSocket>,
+    client: SocketAddr,
+) {
+    let session = match outbound.connect_udp(&target.clone().into()).await {
+        Ok(session) => session,
+        Err(e) => {
+            debug!("Failed to open udp session to {}: {}", target, e);
+            return;
+        }
+    };
+    let (mut send, mut recv) = session.split();
+    let result = tokio::select! {
+        result = uplink(&mut datagrams, &mut send) => result,
+        result = downlink(&mut recv, &target, &socket, client) => result,
+    };
+    match result {
+        Ok(()) => debug!("Udp session from {} to {} closed", client, target),
+        Err(e) => debug!("Udp session from {} to {} failed: {}", client, target, e),
+    }
+}
+
+async fn uplink(
+    datagrams: &mut mpsc::Receiver<Vec<u8>>,
+    send: &mut ProxyUdpSendHalf,
+) -> io::Result<()> {
+    while let Some(datagram) = datagrams.recv().await {
+        send.send(&datagram).await?;
+    }
+    Ok(())
+}
+
+/// send the datagrams of target to the client, prefixed by the header with target as source
+async fn downlink(
+    recv: &mut ProxyUdpRecvHalf,
+    target: &Address,
+    socket: &UdpSocket,
+    client: SocketAddr,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; HEADER_PREFIX_SIZE];
+    buf.extend_from_slice(&target.to_bytes());
+    let header_len = buf.len();
+    buf.resize(header_len + MAX_DATAGRAM_SIZE, 0);
+    loop {
+        let n = recv.recv(&mut buf[header_len..]).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        socket.send_to(&buf[..header_len + n], client).await?;
+    }
+}
+
+/// wait until the client closes the connection, data sent on it is discarded
+async fn wait_closed(control: &mut TcpStream) -> io::Result<()> {
+    let mut buf = [0u8; 64];
+    while control.read(&mut buf).await? > 0 {}
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn parse_ipv4_datagram() {
+        let buf = [0, 0, 0, 1, 127, 0, 0, 1, 0x1f, 0x90, b'h', b'i'];
+        let (target, data) = parse_datagram(&buf).unwrap();
+        assert_eq!(target, Address::SocketAddr(([127, 0, 0, 1], 8080).into()));
+        assert_eq!(data, b"hi");
+    }
+
+    #[test]
+    fn parse_ipv6_datagram() {
+        let mut buf = vec![0, 0, 0, 4];
+        buf.extend_from_slice(&[0; 15]);
+        buf.extend_from_slice(&[1, 0, 53, b'q']);
+        let (target, data) = parse_datagram(&buf).unwrap();
+        assert_eq!(
+            target,
+            Address::SocketAddr((std::net::Ipv6Addr::LOCALHOST, 53).into())
+        );
+        assert_eq!(data, b"q");
+    }
+
+    #[test]
+    fn parse_domain_datagram() {
+        let mut buf = vec![0, 0, 0, 3, 11];
+        buf.extend_from_slice(b"example.com");
+        buf.extend_from_slice(&[0, 53]);
+        let (target, data) = parse_datagram(&buf).unwrap();
+        assert_eq!(target, Address::DomainName("example.com".to_string(), 53));
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn parse_rejects_fragments() {
+        let buf = [0, 0, 1, 1, 127, 0, 0, 1, 0, 53, b'x'];
+        let e = parse_datagram(&buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn parse_rejects_truncated_header() {
+        let e = parse_datagram(&[0, 0]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn nat_sessions_end_with_the_control_connection() {
+        let localhost = IpAddr::from([127, 0, 0, 1]);
+        let listener = TcpListener::bind((localhost, 0)).await.unwrap();
+        let control_client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut control, _) = listener.accept().await.unwrap();
+
+        let request = Address::SocketAddr((localhost, 0).into());
+        let association = UdpAssociation::bind(localhost, localhost, &request, Outbound::Direct)
+            .await
+            .unwrap();
+        let relay = association.local_addr().unwrap();
+        let run = tokio::spawn(async move { association.run(&mut control).await });
+
+        // a datagram through the relay opens a session to the target
+        let target = UdpSocket::bind((localhost, 0)).await.unwrap();
+        let client = UdpSocket::bind((localhost, 0)).await.unwrap();
+        let mut datagram = vec![0, 0, 0];
+        datagram.extend_from_slice(&Address::SocketAddr(target.local_addr().unwrap()).to_bytes());
+        datagram.extend_from_slice(b"ping");
+        client.send_to(&datagram, relay).await.unwrap();
+        let mut buf = [0u8; 64];
+        let (n, session) = target.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+
+        drop(control_client);
+        tokio::time::timeout(Duration::from_secs(1), run)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        // the session socket is closed once its queue is dropped, datagrams to it are refused
+        target.connect(session).await.unwrap();
+        for _ in 0..100 {
+            target.send(b"pong").await.unwrap();
+            match tokio::time::timeout(Duration::from_millis(10), target.recv(&mut buf)).await {
+                Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionRefused => return,
+                _ => {}
+            }
+        }
+        panic!("the udp session outlived its association");
+    }
+}
@@ -1,22 +1,41 @@
-use aes::cipher::{generic_array::GenericArray, BlockEncrypt};
+use std::{collections::HashSet, fmt, io, mem};
+
+use aes::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt};
 use aes_gcm::{aead::Payload, Nonce};
-use chrono::Utc;
 use crc::{Crc, CRC_32_ISO_HDLC};
 use log::trace;
 use md5::{Digest, Md5};
-use rand::Rng;
+use rand::{Rng, RngCore};
 use uuid::Uuid;
 
-use crate::crypto::kdf;
+use crate::crypto::{
+    kdf, KDF_SALT_AEAD_RESP_HEADER_LEN_IV, KDF_SALT_AEAD_RESP_HEADER_LEN_KEY,
+    KDF_SALT_AEAD_RESP_HEADER_PAYLOAD_IV, KDF_SALT_AEAD_RESP_HEADER_PAYLOAD_KEY,
+    KDF_SALT_AUTH_ID_ENCRYPTION_KEY, KDF_SALT_VMESS_HEADER_PAYLOAD_AEAD_IV,
+    KDF_SALT_VMESS_HEADER_PAYLOAD_AEAD_KEY, KDF_SALT_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV,
+    KDF_SALT_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY,
+};
 
-#[derive(Debug, Default, Clone, Copy)]
-pub(crate) struct ID {
+#[derive(Default, Clone, Copy)]
+pub struct ID {
     pub(crate) id: Uuid, // some what the id is a u8 array that length is 16
     pub(crate) cmd_key: [u8; 16],
+    /// key encrypting the auth ids, derived from the cmd key
+    pub(crate) auth_id_key: [u8; 16],
+}
+
+impl fmt::Debug for ID {
+    /// the uuid and the keys are credentials, they are not printed
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ID").finish_non_exhaustive()
+    }
 }
 
 const HASH_SEED: &str = "c48619fe-8f02-49e0-b9e9-edf763e17e21";
 
+/// max difference between the timestamp in the auth id and the current time
+const AUTH_ID_TIMESTAMP_DELTA: i64 = 120;
+
 impl ID {
     /// Generate a new ID for given user id
     /// the cmd key is compose by a md5 hash of two parts
@@ -36,35 +55,29 @@ impl ID {
         let cmd_key = md5_hasher.finalize();
         trace!("cmd_key: {:?}", cmd_key);
 
+        let cmd_key: [u8; 16] = cmd_key.into();
+        let auth_id_key = kdf(&cmd_key, &[KDF_SALT_AUTH_ID_ENCRYPTION_KEY])[..16]
+            .try_into()
+            .expect("length is 16");
         Self {
             id,
-            cmd_key: cmd_key.to_vec().try_into().unwrap(),
+            cmd_key,
+            auth_id_key,
         }
     }
 }
 
 #[derive(Debug, Default, Clone, Copy)]
-pub(crate) struct EAuID {
+pub struct EAuID {
     timestamp: u64,
     random: [u8; 4],
     crc: [u8; 4],
 }
 
 impl EAuID {
-    /// Generate a new EAuID
-    pub fn new(timestamp: Option<u64>, random: Option<[u8; 4]>) -> Self {
-        // check if the timestamp is valid
-        let timestamp = match timestamp {
-            Some(t) => t,
-            None => Utc::now().timestamp() as u64,
-        };
+    /// Generate a new EAuID of timestamp (unix seconds) and random
+    pub fn new(timestamp: u64, random: [u8; 4]) -> Self {
         trace!("timestamp: {}", timestamp);
-
-        // check random
-        let random = match random {
-            Some(r) => r,
-            None => rand::thread_rng().gen(),
-        };
         let calculate_buffer = [timestamp.to_be_bytes().as_slice(), random.as_slice()].concat();
         trace!("calculate_buffer: {:?}", calculate_buffer);
 
@@ -92,46 +105,129 @@ impl EAuID {
         .expect("length is 16")
     }
 
-    pub fn encrypt(&self, key: &[u8; 16]) -> [u8; 16] {
-        // key should add salt
-        let key = kdf(key.as_ref(), vec![b"AES Auth ID Encryption".to_vec()]);
-        trace!("key after kdf: {:?}", key);
-        assert_eq!(
-            key,
-            vec![
-                130, 13, 246, 219, 49, 125, 34, 40, 145, 67, 196, 93, 14, 181, 70, 54, 205, 247,
-                114, 68, 46, 5, 244, 195, 165, 84, 229, 110, 123, 39, 141, 58
-            ],
-            "key is not correct"
-        );
-        let key: [u8; 16] = key.as_slice()[..16].try_into().expect("length is 16");
-
-        let cipher = <aes::Aes128 as aes_gcm::KeyInit>::new((&key).into());
-
+    /// encrypt the auth id with the auth id key of id
+    pub fn encrypt(&self, id: &ID) -> [u8; 16] {
+        let cipher = Self::cipher(id);
         let mut block = GenericArray::from(self.to_bytes());
         cipher.encrypt_block(&mut block);
         block.into()
     }
+
+    /// decrypt an auth id encrypted by id, return None if the crc does not match
+    pub fn decrypt(auth_id: &[u8; 16], id: &ID) -> Option<Self> {
+        let cipher = Self::cipher(id);
+        let mut block = GenericArray::from(*auth_id);
+        cipher.decrypt_block(&mut block);
+
+        let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC)
+            .checksum(&block[..12])
+            .to_be_bytes();
+        if block[12..] != crc {
+            return None;
+        }
+        Some(EAuID {
+            timestamp: u64::from_be_bytes(block[..8].try_into().expect("length is 8")),
+            random: block[8..12].try_into().expect("length is 4"),
+            crc,
+        })
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    fn cipher(id: &ID) -> aes::Aes128 {
+        <aes::Aes128 as aes_gcm::KeyInit>::new((&id.auth_id_key).into())
+    }
+}
+
+/// Remembers the auth ids seen recently so that a captured header can't be replayed.
+///
+/// The ids are kept in two buckets, the older one is dropped every interval,
+/// so an id is remembered for at least one interval and at most two.
+#[derive(Debug)]
+pub struct ReplayFilter {
+    interval: u64,
+    rotated_at: u64,
+    current: HashSet<[u8; 16]>,
+    previous: HashSet<[u8; 16]>,
+}
+
+impl ReplayFilter {
+    /// create a filter which remembers auth ids as long as their timestamps are valid
+    pub fn new() -> Self {
+        Self::with_interval(2 * AUTH_ID_TIMESTAMP_DELTA as u64)
+    }
+
+    /// create a filter which remembers auth ids for at least interval seconds
+    pub fn with_interval(interval: u64) -> Self {
+        Self {
+            interval,
+            rotated_at: 0,
+            current: HashSet::new(),
+            previous: HashSet::new(),
+        }
+    }
+
+    /// record the auth id seen at now (unix seconds), return false if it was already seen
+    pub fn check(&mut self, auth_id: &[u8; 16], now: u64) -> bool {
+        if now >= self.rotated_at + self.interval {
+            self.previous = mem::take(&mut self.current);
+            if now >= self.rotated_at + 2 * self.interval {
+                self.previous.clear();
+            }
+            self.rotated_at = now;
+        }
+        if self.previous.contains(auth_id) {
+            return false;
+        }
+        self.current.insert(*auth_id)
+    }
+}
+
+impl Default for ReplayFilter {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[derive(Debug, Default, Clone, Copy)]
-pub(crate) struct AEADHeader {
+pub struct AEADHeader {
     pub(crate) au_id: EAuID,
+    pub(crate) nonce: [u8; 8],
 }
 
 impl AEADHeader {
-    pub fn new() -> Self {
+    /// size of the auth id
+    pub const AUTH_ID_SIZE: usize = 16;
+    /// size of the encrypted length
+    pub const LENGTH_SIZE: usize = 2 + 16;
+    /// size of the nonce
+    pub const NONCE_SIZE: usize = 8;
+    /// size of the auth id, the encrypted length and the nonce, which come before the payload
+    pub const PREFIX_SIZE: usize = Self::AUTH_ID_SIZE + Self::LENGTH_SIZE + Self::NONCE_SIZE;
+    /// size of the tag after the encrypted payload
+    pub const TAG_SIZE: usize = 16;
+
+    /// create a header with the auth id of now (unix seconds), the randoms are drawn from rng
+    pub fn new(now: i64, rng: &mut dyn RngCore) -> Self {
         AEADHeader {
-            au_id: EAuID::new(None, None),
+            au_id: EAuID::new(now as u64, rng.gen()),
+            nonce: rng.gen(),
         }
     }
 
+    /// create a header from its auth id and nonce
+    pub fn from_parts(au_id: EAuID, nonce: [u8; 8]) -> Self {
+        AEADHeader { au_id, nonce }
+    }
+
     pub fn seal(&self, id: ID, data: &[u8]) -> Vec<u8> {
         let key = id.cmd_key;
-        let au_id = self.au_id.encrypt(&key);
+        let au_id = self.au_id.encrypt(&id);
         trace!("au_id: {:?}", au_id);
 
-        let nonce: [u8; 8] = rand::thread_rng().gen();
+        let nonce = self.nonce;
         let mut aead_payload_length_serialize_buffer = Vec::new();
 
         let header_payload_data_len = data.len() as u16;
@@ -146,10 +242,10 @@ impl AEADHeader {
             use aes_gcm::{aead::Aead, KeyInit};
             let payload_header_length_aead_key = &kdf(
                 key.as_ref(),
-                vec![
-                    b"VMess Header AEAD Key_Length".to_vec(),
-                    au_id.to_vec(),
-                    nonce.to_vec(),
+                &[
+                    KDF_SALT_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY,
+                    &au_id,
+                    &nonce,
                 ],
             )[..16];
             trace!(
@@ -159,11 +255,7 @@ impl AEADHeader {
 
             let payload_header_length_aead_nonce = &kdf(
                 key.as_ref(),
-                vec![
-                    b"VMess Header AEAD Nonce_Length".to_vec(),
-                    au_id.to_vec(),
-                    nonce.to_vec(),
-                ],
+                &[KDF_SALT_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV, &au_id, &nonce],
             )[..12];
             trace!(
                 "payload_header_length_aead_nonce: {:?}",
@@ -192,20 +284,12 @@ impl AEADHeader {
             use aes_gcm::{aead::Aead, KeyInit};
             let payload_header_aead_key = &kdf(
                 key.as_ref(),
-                vec![
-                    b"VMess Header AEAD Key".to_vec(),
-                    au_id.to_vec(),
-                    nonce.to_vec(),
-                ],
+                &[KDF_SALT_VMESS_HEADER_PAYLOAD_AEAD_KEY, &au_id, &nonce],
             )[..16];
 
             let payload_header_aead_nonce = &kdf(
                 key.as_ref(),
-                vec![
-                    b"VMess Header AEAD Nonce".to_vec(),
-                    au_id.to_vec(),
-                    nonce.to_vec(),
-                ],
+                &[KDF_SALT_VMESS_HEADER_PAYLOAD_AEAD_IV, &au_id, &nonce],
             )[..12];
 
             let payload_header_aead = aes_gcm::Aes128Gcm::new(payload_header_aead_key.into());
@@ -229,3 +313,274 @@ impl AEADHeader {
         output_buffer
     }
 }
+
+impl AEADHeader {
+    /// open a sealed header of one of ids received at now (unix seconds),
+    /// return the index of the id and the command section,
+    /// the auth id must not have been seen by filter
+    pub fn open(
+        ids: &[ID],
+        data: &[u8],
+        now: i64,
+        filter: &mut ReplayFilter,
+    ) -> io::Result<(usize, Vec<u8>)> {
+        if data.len() < Self::PREFIX_SIZE + Self::TAG_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "header too short",
+            ));
+        }
+        let (prefix, payload) = data.split_at(Self::PREFIX_SIZE);
+        let auth_id: &[u8; 16] = prefix[..16].try_into().expect("length is 16");
+        let (index, _) = Self::match_auth_id(ids, auth_id, now)?;
+        if !filter.check(auth_id, now as u64) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "replayed auth id",
+            ));
+        }
+
+        let nonce: &[u8; 8] = prefix[34..].try_into().expect("length is 8");
+        let length = Self::open_length(&ids[index], auth_id, nonce, &prefix[16..34])?;
+        if payload.len() != length as usize + Self::TAG_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("header length mismatch, expect {}", length),
+            ));
+        }
+        let header = Self::open_payload(&ids[index], auth_id, nonce, payload)?;
+        Ok((index, header))
+    }
+
+    /// find the id whose cmd key decrypts the auth id to a valid one,
+    /// its timestamp must be within 120 seconds of now (unix seconds)
+    pub fn match_auth_id(ids: &[ID], auth_id: &[u8; 16], now: i64) -> io::Result<(usize, EAuID)> {
+        let (index, eauid) = ids
+            .iter()
+            .enumerate()
+            .find_map(|(index, id)| EAuID::decrypt(auth_id, id).map(|e| (index, e)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no matched user"))?;
+        let delta = now - eauid.timestamp as i64;
+        if delta.abs() > AUTH_ID_TIMESTAMP_DELTA {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid auth id timestamp {}, now {}", eauid.timestamp, now),
+            ));
+        }
+        Ok((index, eauid))
+    }
+
+    /// decrypt the length of the command section
+    pub fn open_length(
+        id: &ID,
+        auth_id: &[u8; 16],
+        nonce: &[u8; 8],
+        data: &[u8],
+    ) -> io::Result<u16> {
+        let length = Self::open_with(
+            id,
+            KDF_SALT_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY,
+            KDF_SALT_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV,
+            auth_id,
+            nonce,
+            data,
+        )?;
+        let length: [u8; 2] = length
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid header length"))?;
+        Ok(u16::from_be_bytes(length))
+    }
+
+    /// decrypt the command section
+    pub fn open_payload(
+        id: &ID,
+        auth_id: &[u8; 16],
+        nonce: &[u8; 8],
+        data: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        Self::open_with(
+            id,
+            KDF_SALT_VMESS_HEADER_PAYLOAD_AEAD_KEY,
+            KDF_SALT_VMESS_HEADER_PAYLOAD_AEAD_IV,
+            auth_id,
+            nonce,
+            data,
+        )
+    }
+
+    fn open_with(
+        id: &ID,
+        key_salt: &[u8],
+        nonce_salt: &[u8],
+        auth_id: &[u8; 16],
+        nonce: &[u8; 8],
+        data: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        use aes_gcm::{aead::Aead, KeyInit};
+        let aead_key = &kdf(&id.cmd_key, &[key_salt, auth_id, nonce])[..16];
+        let aead_nonce = &kdf(&id.cmd_key, &[nonce_salt, auth_id, nonce])[..12];
+
+        let cipher = aes_gcm::Aes128Gcm::new(aead_key.into());
+        let payload = Payload {
+            msg: data,
+            aad: auth_id.as_ref(),
+        };
+        cipher
+            .decrypt(Nonce::from_slice(aead_nonce), payload)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "failed to decrypt header"))
+    }
+}
+
+/// The AEAD encrypted response header, keyed by the response body key and iv
+pub(crate) struct AEADResponseHeader {}
+
+impl AEADResponseHeader {
+    /// size of the encrypted length
+    pub const LENGTH_SIZE: usize = 2 + 16;
+    /// size of the tag after the encrypted header
+    pub const TAG_SIZE: usize = 16;
+
+    /// decrypt the length of the response header
+    pub fn open_length(key: &[u8; 16], iv: &[u8; 16], data: &[u8]) -> io::Result<u16> {
+        let length = Self::open_with(
+            key,
+            iv,
+            KDF_SALT_AEAD_RESP_HEADER_LEN_KEY,
+            KDF_SALT_AEAD_RESP_HEADER_LEN_IV,
+            data,
+        )?;
+        let length: [u8; 2] = length.try_into().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "invalid response header length")
+        })?;
+        Ok(u16::from_be_bytes(length))
+    }
+
+    /// decrypt the response header
+    pub fn open(key: &[u8; 16], iv: &[u8; 16], data: &[u8]) -> io::Result<Vec<u8>> {
+        Self::open_with(
+            key,
+            iv,
+            KDF_SALT_AEAD_RESP_HEADER_PAYLOAD_KEY,
+            KDF_SALT_AEAD_RESP_HEADER_PAYLOAD_IV,
+            data,
+        )
+    }
+
+    /// encrypt the response header, prefixed by its encrypted length
+    pub fn seal(key: &[u8; 16], iv: &[u8; 16], header: &[u8]) -> Vec<u8> {
+        let length = (header.len() as u16).to_be_bytes();
+        let mut output_buffer = Self::seal_with(
+            key,
+            iv,
+            KDF_SALT_AEAD_RESP_HEADER_LEN_KEY,
+            KDF_SALT_AEAD_RESP_HEADER_LEN_IV,
+            &length,
+        );
+        output_buffer.extend_from_slice(&Self::seal_with(
+            key,
+            iv,
+            KDF_SALT_AEAD_RESP_HEADER_PAYLOAD_KEY,
+            KDF_SALT_AEAD_RESP_HEADER_PAYLOAD_IV,
+            header,
+        ));
+        output_buffer
+    }
+
+    fn seal_with(
+        key: &[u8; 16],
+        iv: &[u8; 16],
+        key_salt: &[u8],
+        iv_salt: &[u8],
+        data: &[u8],
+    ) -> Vec<u8> {
+        use aes_gcm::{aead::Aead, KeyInit};
+        let aead_key = &kdf(key, &[key_salt])[..16];
+        let aead_nonce = &kdf(iv, &[iv_salt])[..12];
+
+        let cipher = aes_gcm::Aes128Gcm::new(aead_key.into());
+        cipher
+            .encrypt(Nonce::from_slice(aead_nonce), data)
+            .expect("encryption failure!")
+    }
+
+    fn open_with(
+        key: &[u8; 16],
+        iv: &[u8; 16],
+        key_salt: &[u8],
+        iv_salt: &[u8],
+        data: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        use aes_gcm::{aead::Aead, KeyInit};
+        let aead_key = &kdf(key, &[key_salt])[..16];
+        let aead_nonce = &kdf(iv, &[iv_salt])[..12];
+
+        let cipher = aes_gcm::Aes128Gcm::new(aead_key.into());
+        cipher
+            .decrypt(Nonce::from_slice(aead_nonce), data)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "failed to decrypt response header",
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+    const COMMAND: &[u8] = b"command section";
+
+    fn ids() -> [ID; 2] {
+        [
+            ID::new(Uuid::from_u128(1)),
+            ID::new(Uuid::parse_str("231c2fc0-f8c4-4248-b098-21f0dd78c810").unwrap()),
+        ]
+    }
+
+    fn sealed(id: ID) -> Vec<u8> {
+        AEADHeader::new(NOW, &mut StdRng::seed_from_u64(0)).seal(id, COMMAND)
+    }
+
+    #[test]
+    fn seal_then_open() {
+        let ids = ids();
+        let (index, header) =
+            AEADHeader::open(&ids, &sealed(ids[1]), NOW + 60, &mut ReplayFilter::new()).unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(header, COMMAND);
+    }
+
+    #[test]
+    fn open_rejects_expired_auth_id() {
+        let ids = ids();
+        let now = NOW + AUTH_ID_TIMESTAMP_DELTA + 1;
+        let e = AEADHeader::open(&ids, &sealed(ids[0]), now, &mut ReplayFilter::new()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn open_rejects_replayed_auth_id() {
+        let ids = ids();
+        let sealed = sealed(ids[0]);
+        let mut filter = ReplayFilter::new();
+        assert!(AEADHeader::open(&ids, &sealed, NOW, &mut filter).is_ok());
+        let e = AEADHeader::open(&ids, &sealed, NOW + 1, &mut filter).unwrap_err();
+        assert_eq!(e.to_string(), "replayed auth id");
+    }
+
+    #[test]
+    fn replay_filter_forgets_after_two_intervals() {
+        let mut filter = ReplayFilter::with_interval(10);
+        let auth_id = [7u8; 16];
+        assert!(filter.check(&auth_id, 100));
+        assert!(!filter.check(&auth_id, 105));
+        // rotated once, still remembered
+        assert!(!filter.check(&auth_id, 110));
+        assert!(filter.check(&auth_id, 130));
+    }
+}
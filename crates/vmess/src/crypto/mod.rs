@@ -1,170 +1,246 @@
 pub(crate) mod fnv;
+pub(crate) mod shake;
 
-use dyn_clone::DynClone;
-use sha2::{Digest, Sha256};
-use std::iter::Iterator;
-
-/// Hash is a trait which emulates the hash.Hash interface in Go.
-trait Hash: DynClone {
-    /// write adds more data to the running hash.
-    fn write(&mut self, data: Vec<u8>);
+use std::sync::LazyLock;
 
-    /// sum appends the current hash to b and returns the resulting slice.
-    /// This does not change the underlying hash state.
-    fn sum(&mut self, i: Option<Vec<u8>>) -> Vec<u8>;
+use md5::Md5;
+use sha2::{Digest, Sha256};
 
-    /// reset resets the Hash to its initial state.
-    fn reset(&mut self);
+/// salt of the root of every kdf path
+const KDF_SALT_CONST_VMESS_AEAD_KDF: &[u8] = b"VMess AEAD KDF";
+
+pub(crate) const KDF_SALT_AUTH_ID_ENCRYPTION_KEY: &[u8] = b"AES Auth ID Encryption";
+pub(crate) const KDF_SALT_AEAD_RESP_HEADER_LEN_KEY: &[u8] = b"AEAD Resp Header Len Key";
+pub(crate) const KDF_SALT_AEAD_RESP_HEADER_LEN_IV: &[u8] = b"AEAD Resp Header Len IV";
+pub(crate) const KDF_SALT_AEAD_RESP_HEADER_PAYLOAD_KEY: &[u8] = b"AEAD Resp Header Key";
+pub(crate) const KDF_SALT_AEAD_RESP_HEADER_PAYLOAD_IV: &[u8] = b"AEAD Resp Header IV";
+pub(crate) const KDF_SALT_VMESS_HEADER_PAYLOAD_AEAD_KEY: &[u8] = b"VMess Header AEAD Key";
+pub(crate) const KDF_SALT_VMESS_HEADER_PAYLOAD_AEAD_IV: &[u8] = b"VMess Header AEAD Nonce";
+pub(crate) const KDF_SALT_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY: &[u8] =
+    b"VMess Header AEAD Key_Length";
+pub(crate) const KDF_SALT_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV: &[u8] =
+    b"VMess Header AEAD Nonce_Length";
+pub(crate) const KDF_SALT_AUTH_LEN: &[u8] = b"auth_len";
+
+/// salts starting the kdf paths, their hmacs are computed once
+const KDF_SALTS: [&[u8]; 10] = [
+    KDF_SALT_AUTH_ID_ENCRYPTION_KEY,
+    KDF_SALT_AEAD_RESP_HEADER_LEN_KEY,
+    KDF_SALT_AEAD_RESP_HEADER_LEN_IV,
+    KDF_SALT_AEAD_RESP_HEADER_PAYLOAD_KEY,
+    KDF_SALT_AEAD_RESP_HEADER_PAYLOAD_IV,
+    KDF_SALT_VMESS_HEADER_PAYLOAD_AEAD_KEY,
+    KDF_SALT_VMESS_HEADER_PAYLOAD_AEAD_IV,
+    KDF_SALT_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_KEY,
+    KDF_SALT_VMESS_HEADER_PAYLOAD_LENGTH_AEAD_IV,
+    KDF_SALT_AUTH_LEN,
+];
+
+/// block size of sha256, and so of every hmac in the chain
+const BLOCK_SIZE: usize = 64;
+
+/// A hash in the kdf chain, sha256 at the root and hmacs nested over it above.
+trait KdfHash: Clone {
+    /// add more data to the running hash
+    fn write(&mut self, data: &[u8]);
+
+    /// consume the hash and return the digest
+    fn sum(self) -> [u8; 32];
+}
 
-    /// size returns the number of bytes Sum will return.
-    fn size(&self) -> usize;
+impl KdfHash for Sha256 {
+    fn write(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
 
-    /// block_size returns the hash's underlying block size.
-    fn block_size(&self) -> usize;
+    fn sum(self) -> [u8; 32] {
+        Digest::finalize(self).into()
+    }
 }
 
-/// Hmac is a struct which emulates the hmac.Hmac struct in Go.
-/// marshalable option is not implemented.
+/// HMAC keyed by one element of the kdf path, over the hash H of the previous elements.
 #[derive(Clone)]
-struct Hmac {
-    /// opad is the outer padding.
-    opad: Vec<u8>,
-    /// ipad is the inner padding.
-    ipad: Vec<u8>,
-    /// inner is the inner hash.
-    inner: Box<dyn Hash>,
-    /// outer is the outer hash.
-    outer: Box<dyn Hash>,
+struct HmacLevel<H> {
+    /// inner hash, the ipad is already written
+    inner: H,
+    /// outer hash, the opad is already written
+    outer: H,
 }
 
-impl Hash for Hmac {
-    fn write(&mut self, data: Vec<u8>) {
-        self.inner.write(data);
-    }
+impl<H: KdfHash> HmacLevel<H> {
+    /// key an hmac over base, which is the initial state of H
+    fn new(base: &H, key: &[u8]) -> Self {
+        let mut block = [0u8; BLOCK_SIZE];
+        if key.len() > BLOCK_SIZE {
+            let mut hasher = base.clone();
+            hasher.write(key);
+            block[..32].copy_from_slice(&hasher.sum());
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
 
-    fn sum(&mut self, input: Option<Vec<u8>>) -> Vec<u8> {
-        // TODO: we shoul check if i is none
-        let (orig_len, input) = match input {
-            Some(i) => (i.len(), i),
-            None => (0, vec![].to_vec()),
-        };
-        let input = self.inner.sum(Some(input));
-        self.outer.reset();
-        self.outer.write(self.opad.clone());
-
-        self.outer.write(input[orig_len..].to_vec());
-        self.outer.sum(Some(input[0..orig_len].to_vec()))
+        let mut inner = base.clone();
+        inner.write(&block.map(|b| b ^ 0x36));
+        let mut outer = base.clone();
+        outer.write(&block.map(|b| b ^ 0x5c));
+        Self { inner, outer }
     }
+}
 
-    fn reset(&mut self) {
-        self.inner.reset();
-        self.inner.write(self.ipad.clone());
-        self.outer.reset();
-        self.outer.write(self.opad.clone());
+impl<H: KdfHash> KdfHash for HmacLevel<H> {
+    fn write(&mut self, data: &[u8]) {
+        self.inner.write(data);
     }
 
-    fn size(&self) -> usize {
-        self.outer.size()
+    fn sum(self) -> [u8; 32] {
+        let mut outer = self.outer;
+        outer.write(&self.inner.sum());
+        outer.sum()
     }
+}
 
-    fn block_size(&self) -> usize {
-        self.inner.block_size()
-    }
+type KdfRoot = HmacLevel<Sha256>;
+
+type KdfPrefix = HmacLevel<KdfRoot>;
+
+/// hmac of the constant salt, shared by every path
+static KDF_ROOT: LazyLock<KdfRoot> =
+    LazyLock::new(|| HmacLevel::new(&Sha256::new(), KDF_SALT_CONST_VMESS_AEAD_KDF));
+
+/// hmacs of the salts in `KDF_SALTS` over the root
+static KDF_PREFIXES: LazyLock<Vec<(&[u8], KdfPrefix)>> = LazyLock::new(|| {
+    KDF_SALTS
+        .iter()
+        .map(|&salt| (salt, HmacLevel::new(&*KDF_ROOT, salt)))
+        .collect()
+});
+
+fn hash<H: KdfHash>(mut hasher: H, data: &[u8]) -> [u8; 32] {
+    hasher.write(data);
+    hasher.sum()
 }
 
-dyn_clone::clone_trait_object!(Hash);
-
-impl Hmac {
-    // emulates the hmac.New function in Go.
-    fn new_hasher(hasher: Box<dyn Hash>, mut key: Vec<u8>) -> Box<dyn Hash> {
-        // new hash
-        // FIXME: memory allocation should be optimized.
-        let mut outer = hasher.clone();
-        let mut inner = hasher;
-
-        let block_size = inner.block_size();
-        let mut ipad = vec![0; block_size];
-        let mut opad = vec![0; block_size];
-        if key.len() > block_size {
-            outer.write(key);
-            key = outer.sum(None).to_vec();
+/// calculate the kdf for given key and path, the path has at most 3 elements
+pub(crate) fn kdf(key: &[u8], path: &[&[u8]]) -> [u8; 32] {
+    let Some((&salt, rest)) = path.split_first() else {
+        return hash(KDF_ROOT.clone(), key);
+    };
+    let uncached;
+    let prefix = match KDF_PREFIXES.iter().find(|(s, _)| *s == salt) {
+        Some((_, prefix)) => prefix,
+        None => {
+            uncached = HmacLevel::new(&*KDF_ROOT, salt);
+            &uncached
         }
+    };
 
-        ipad[0..key.len()].copy_from_slice(key.as_slice());
-        (0..ipad.len()).for_each(|i| {
-            ipad[i] ^= 0x36;
-        });
-        opad[0..key.len()].copy_from_slice(key.as_slice());
-        (0..opad.len()).for_each(|i| {
-            opad[i] ^= 0x5c;
-        });
-
-        inner.write(ipad.clone());
-
-        Box::new(Hmac {
-            opad,
-            ipad: ipad.to_vec(),
-            inner,
-            outer,
-        })
+    match rest {
+        [] => hash(prefix.clone(), key),
+        [a] => hash(HmacLevel::new(prefix, a), key),
+        [a, b] => hash(HmacLevel::new(&HmacLevel::new(prefix, a), b), key),
+        _ => panic!("kdf path longer than 3 is not supported"),
     }
 }
 
-/// Sha256Hasher wraps the sha256 hasher in sha2. It implements the Hash trait.
-#[derive(Clone)]
-struct Sha256Hasher {
-    hasher: Sha256,
+/// expand the 16 bytes body key to the 32 bytes key of ChaCha20-Poly1305:
+/// md5(key) followed by md5(md5(key))
+pub(crate) fn chacha20poly1305_key(key: &[u8; 16]) -> [u8; 32] {
+    let first = Md5::digest(key);
+    let second = Md5::digest(first);
+
+    let mut expanded = [0u8; 32];
+    expanded[..16].copy_from_slice(&first);
+    expanded[16..].copy_from_slice(&second);
+    expanded
 }
 
-impl Hash for Sha256Hasher {
-    fn write(&mut self, data: Vec<u8>) {
-        self.hasher.update(data);
+/// whether the cpu has hardware acceleration for AES-GCM, AES-NI with CLMUL on x86
+/// and the ARMv8 crypto extension on aarch64
+pub(crate) fn has_aes_hardware() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        std::arch::is_x86_feature_detected!("aes")
+            && std::arch::is_x86_feature_detected!("pclmulqdq")
     }
-
-    fn sum(&mut self, input: Option<Vec<u8>>) -> Vec<u8> {
-        let mut hasher = self.hasher.clone();
-        if let Some(i) = input {
-            hasher.update(i);
-        }
-        hasher.finalize().to_vec()
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
     }
-
-    fn reset(&mut self) {
-        self.hasher = Sha256::new();
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
     }
+}
 
-    fn size(&self) -> usize {
-        32
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chacha20poly1305_key_is_md5_expanded() {
+        let key: [u8; 16] = std::array::from_fn(|i| i as u8);
+        assert_eq!(
+            chacha20poly1305_key(&key),
+            [
+                0x1a, 0xc1, 0xef, 0x01, 0xe9, 0x6c, 0xaf, 0x1b, 0xe0, 0xd3, 0x29, 0x33, 0x1a, 0x4f,
+                0xc2, 0xa8, 0xe0, 0x54, 0x2d, 0xb5, 0x41, 0x8c, 0x43, 0xd2, 0x56, 0xa6, 0xa6, 0x43,
+                0xaf, 0xa5, 0x53, 0xfe
+            ]
+        );
     }
 
-    fn block_size(&self) -> usize {
-        64
+    const KEY: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+    #[test]
+    fn kdf_without_path() {
+        assert_eq!(
+            kdf(&KEY, &[]),
+            [
+                0xeb, 0xdb, 0x90, 0x98, 0x29, 0x82, 0x0c, 0x28, 0x7b, 0x7d, 0x76, 0x01, 0xfe, 0x00,
+                0xd5, 0xb0, 0x93, 0xf4, 0x48, 0x5a, 0x02, 0x73, 0x11, 0xc2, 0x20, 0x5e, 0x90, 0x07,
+                0xd5, 0xfc, 0x9c, 0x16
+            ]
+        );
     }
-}
 
-impl Sha256Hasher {
-    fn new() -> Self {
-        Self {
-            hasher: Sha256::new(),
-        }
+    #[test]
+    fn kdf_cached_salt() {
+        assert_eq!(
+            kdf(&KEY, &[KDF_SALT_AUTH_ID_ENCRYPTION_KEY]),
+            [
+                0x9f, 0xa4, 0x28, 0x9c, 0x41, 0x65, 0x08, 0x61, 0xa4, 0x5b, 0x34, 0xae, 0xab, 0x38,
+                0x79, 0xfe, 0x47, 0x85, 0xdc, 0xe5, 0x7a, 0xb3, 0xf6, 0x8c, 0xfb, 0x0c, 0xc6, 0x0f,
+                0xca, 0x69, 0x46, 0x0a
+            ]
+        );
     }
-}
 
-/// calculate the kdf for given key and path
-pub(crate) fn kdf(key: &[u8], path: Vec<Vec<u8>>) -> Vec<u8> {
-    // TODO: hard coded token shoule be in a seperate file
-    let mut mac = Hmac::new_hasher(
-        Box::new(Sha256Hasher::new()),
-        "VMess AEAD KDF".as_bytes().to_vec(),
-    );
-
-    for x in path.iter() {
-        // feed the calculated mac1 to a new hasher, in reverse order
-        let new_mac = Hmac::new_hasher(mac, x.to_vec());
-        mac = new_mac;
+    #[test]
+    fn kdf_three_elements() {
+        let auth_id: [u8; 16] = std::array::from_fn(|i| 16 + i as u8);
+        let nonce: [u8; 8] = std::array::from_fn(|i| 32 + i as u8);
+        assert_eq!(
+            kdf(
+                &KEY,
+                &[KDF_SALT_VMESS_HEADER_PAYLOAD_AEAD_KEY, &auth_id, &nonce]
+            ),
+            [
+                0x09, 0xa9, 0xa6, 0xb3, 0xaa, 0x1c, 0xc3, 0x39, 0x5e, 0x9b, 0xb2, 0x8a, 0x66, 0x2b,
+                0xa9, 0xdb, 0x57, 0x08, 0xd1, 0x2e, 0x98, 0x60, 0x84, 0x22, 0x9e, 0x71, 0x8c, 0x2f,
+                0x0b, 0xa6, 0x0d, 0x7e
+            ]
+        );
     }
 
-    mac.write(key.to_vec());
-
-    mac.sum(None)
+    #[test]
+    fn kdf_uncached_salt_longer_than_a_block() {
+        assert_eq!(
+            kdf(&KEY, &[&[b'x'; 100]]),
+            [
+                0x0b, 0x53, 0xa0, 0x04, 0xfd, 0x3f, 0xeb, 0x6b, 0xff, 0x86, 0x77, 0x46, 0x04, 0x70,
+                0x03, 0x56, 0xfd, 0x01, 0x3d, 0xe0, 0x4b, 0x32, 0x19, 0xdd, 0x2a, 0xe2, 0x2a, 0xa8,
+                0x16, 0xdc, 0xd1, 0x16
+            ]
+        );
+    }
 }
@@ -0,0 +1,27 @@
+//! Chunk length mask of VMess, a SHAKE128 stream seeded by the body iv
+
+use sha3::{
+    digest::{ExtendableOutput, Update, XofReader},
+    Shake128, Shake128Reader,
+};
+
+pub(crate) struct ShakeMask {
+    reader: Shake128Reader,
+}
+
+impl ShakeMask {
+    pub(crate) fn new(iv: &[u8; 16]) -> Self {
+        let mut hasher = Shake128::default();
+        hasher.update(iv);
+        Self {
+            reader: hasher.finalize_xof(),
+        }
+    }
+
+    /// next 2 bytes of the stream as a big endian u16
+    pub(crate) fn next(&mut self) -> u16 {
+        let mut buf = [0u8; 2];
+        self.reader.read(&mut buf);
+        u16::from_be_bytes(buf)
+    }
+}
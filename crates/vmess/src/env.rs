@@ -0,0 +1,114 @@
+//! Time and randomness of the handshakes
+//!
+//! Everything random in a connection, the keys, the auth id, the nonces and the padding,
+//! is drawn from one rng created per connection. Both the clock and the rng can be replaced,
+//! e.g. by fixed ones to reproduce a handshake byte for byte.
+
+use std::{fmt, sync::Arc};
+
+use chrono::Utc;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+/// Source of the current time
+pub trait Clock: Send + Sync {
+    /// seconds since the unix epoch
+    fn now(&self) -> i64;
+}
+
+/// The system clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        Utc::now().timestamp()
+    }
+}
+
+/// A clock stopped at the given time
+#[derive(Debug, Clone, Copy)]
+pub struct FixedClock(pub i64);
+
+impl Clock for FixedClock {
+    fn now(&self) -> i64 {
+        self.0
+    }
+}
+
+/// Rng of one connection
+pub type ConnectionRng = Box<dyn RngCore + Send>;
+
+/// Creates the rng of each connection
+pub trait RngSource: Send + Sync {
+    fn rng(&self) -> ConnectionRng;
+}
+
+/// Rngs seeded from the thread rng
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ThreadRngSource;
+
+impl RngSource for ThreadRngSource {
+    fn rng(&self) -> ConnectionRng {
+        Box::new(StdRng::from_rng(rand::thread_rng()).expect("thread rng never fails"))
+    }
+}
+
+/// Rngs seeded with the same seed, every connection gets the same random bytes
+#[derive(Debug, Clone, Copy)]
+pub struct SeededRngSource(pub u64);
+
+impl RngSource for SeededRngSource {
+    fn rng(&self) -> ConnectionRng {
+        Box::new(StdRng::seed_from_u64(self.0))
+    }
+}
+
+/// Clock and rng used by the connections, shared by all of them
+#[derive(Clone)]
+pub struct Env {
+    clock: Arc<dyn Clock>,
+    rng_source: Arc<dyn RngSource>,
+    /// seconds added to the time of the clock
+    clock_offset: i64,
+}
+
+impl Env {
+    pub fn new(clock: impl Clock + 'static, rng_source: impl RngSource + 'static) -> Self {
+        Self {
+            clock: Arc::new(clock),
+            rng_source: Arc::new(rng_source),
+            clock_offset: 0,
+        }
+    }
+
+    /// shift the clock by offset seconds, for a local clock drifting away from the server's
+    pub fn with_clock_offset(mut self, offset: i64) -> Self {
+        self.clock_offset = offset;
+        self
+    }
+
+    /// current time in seconds since the unix epoch, with the offset applied
+    pub fn now(&self) -> i64 {
+        self.clock.now() + self.clock_offset
+    }
+
+    /// a new rng for one connection
+    pub fn rng(&self) -> ConnectionRng {
+        self.rng_source.rng()
+    }
+}
+
+impl Default for Env {
+    /// the system clock and rngs seeded from the thread rng
+    fn default() -> Self {
+        Self::new(SystemClock, ThreadRngSource)
+    }
+}
+
+impl fmt::Debug for Env {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Env")
+            .field("clock_offset", &self.clock_offset)
+            .finish_non_exhaustive()
+    }
+}
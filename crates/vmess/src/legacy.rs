@@ -0,0 +1,126 @@
+//! Legacy (alterId) header of VMess, superseded by the AEAD header.
+//!
+//! The client authenticates with a HMAC-MD5 of the timestamp keyed by one of its ids,
+//! then the command section is encrypted with AES-128-CFB keyed by the cmd key.
+//! The server finds the id and the timestamp by trying every second around its time.
+
+use aes::Aes128;
+use cfb_mode::{cipher::KeyIvInit, BufDecryptor, BufEncryptor};
+use hmac::{Hmac, Mac};
+use log::trace;
+use md5::{Digest, Md5};
+use rand::{Rng, RngCore};
+use uuid::Uuid;
+
+use crate::aead::ID;
+
+const ALTER_ID_SEED: &[u8] = b"16167dc8-16b6-4e6d-b8bb-65dd68113a81";
+const ALTER_ID_RETRY_SEED: &[u8] = b"533eff8a-4113-4b10-b5ce-0f5d76b98cd2";
+
+/// max difference between the timestamp in the header and the current time
+const TIMESTAMP_DELTA: i64 = 30;
+
+/// max difference between the timestamp of a header accepted by the server and its time,
+/// like v2ray
+const SERVER_TIMESTAMP_DELTA: i64 = 120;
+
+/// derive the alter ids of a user, each one is the md5 of the previous one and a seed
+pub(crate) fn alter_ids(id: &ID, count: u16) -> Vec<ID> {
+    let mut ids = Vec::with_capacity(count as usize);
+    let mut prev = id.id;
+    for _ in 0..count {
+        let mut md5_hasher = Md5::new();
+        md5_hasher.update(prev.as_bytes());
+        md5_hasher.update(ALTER_ID_SEED);
+        let mut next = Uuid::from_bytes(md5_hasher.clone().finalize().into());
+        while next == prev {
+            md5_hasher.update(ALTER_ID_RETRY_SEED);
+            next = Uuid::from_bytes(md5_hasher.clone().finalize().into());
+        }
+        ids.push(ID::new(next));
+        prev = next;
+    }
+    ids
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LegacyHeader {
+    timestamp: u64,
+}
+
+impl LegacyHeader {
+    /// create a header with a timestamp randomly shifted from now (unix seconds)
+    pub fn new(now: i64, rng: &mut dyn RngCore) -> Self {
+        let delta = rng.gen_range(-TIMESTAMP_DELTA..=TIMESTAMP_DELTA);
+        Self {
+            timestamp: (now + delta) as u64,
+        }
+    }
+
+    /// authenticate with auth_id, which is the user id itself or one of its alter ids,
+    /// and encrypt the command section with the cmd key of the user id
+    pub fn seal(&self, id: &ID, auth_id: &ID, data: &[u8]) -> Vec<u8> {
+        trace!("timestamp: {}", self.timestamp);
+        let auth = self.auth(auth_id);
+
+        let mut command = data.to_vec();
+        BufEncryptor::<Aes128>::new((&id.cmd_key).into(), &self.command_iv().into())
+            .encrypt(&mut command);
+
+        let mut output_buffer = Vec::with_capacity(auth.len() + command.len());
+        output_buffer.extend_from_slice(&auth);
+        output_buffer.extend_from_slice(&command);
+        output_buffer
+    }
+
+    /// find the header authenticated by auth within the time window of the server,
+    /// auth_ids are the ids and the alter ids of the clients with the index of their client,
+    /// return the index of the client and the header
+    pub fn open_auth(auth_ids: &[(usize, ID)], auth: &[u8; 16], now: i64) -> Option<(usize, Self)> {
+        for timestamp in now - SERVER_TIMESTAMP_DELTA..=now + SERVER_TIMESTAMP_DELTA {
+            let header = Self {
+                timestamp: timestamp as u64,
+            };
+            for (index, auth_id) in auth_ids {
+                if header.auth(auth_id) == *auth {
+                    return Some((*index, header));
+                }
+            }
+        }
+        None
+    }
+
+    /// decryptor of the command section following the authentication, id is the user id
+    pub fn command_decryptor(&self, id: &ID) -> BufDecryptor<Aes128> {
+        BufDecryptor::<Aes128>::new((&id.cmd_key).into(), &self.command_iv().into())
+    }
+
+    /// HMAC-MD5 of the timestamp keyed by auth_id
+    fn auth(&self, auth_id: &ID) -> [u8; 16] {
+        let mut mac = <Hmac<Md5> as Mac>::new_from_slice(auth_id.id.as_bytes())
+            .expect("hmac accepts any key length");
+        mac.update(&self.timestamp.to_be_bytes());
+        mac.finalize().into_bytes().into()
+    }
+
+    /// iv of the command section, the md5 of the timestamp repeated 4 times
+    fn command_iv(&self) -> [u8; 16] {
+        let mut md5_hasher = Md5::new();
+        for _ in 0..4 {
+            md5_hasher.update(self.timestamp.to_be_bytes());
+        }
+        md5_hasher.finalize().into()
+    }
+}
+
+/// decryptor of the legacy response header, keyed by the response body key and iv
+pub(crate) fn response_decryptor(key: &[u8; 16], iv: &[u8; 16]) -> BufDecryptor<Aes128> {
+    BufDecryptor::<Aes128>::new(key.into(), iv.into())
+}
+
+/// encrypt the legacy response header with the response body key and iv
+pub(crate) fn seal_response(key: &[u8; 16], iv: &[u8; 16], header: &[u8]) -> Vec<u8> {
+    let mut header = header.to_vec();
+    BufEncryptor::<Aes128>::new(key.into(), iv.into()).encrypt(&mut header);
+    header
+}
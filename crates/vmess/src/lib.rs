@@ -1,8 +1,26 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+    net::SocketAddr,
+    sync::{Arc, LazyLock},
+};
+
+use log::info;
+use uuid::Uuid;
+
 pub mod aead;
 pub mod crypto;
+pub mod env;
+mod legacy;
+pub mod mux;
 pub mod protocol;
+mod server;
 pub mod stream;
+pub mod udp;
+
+pub use server::VmessServer;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum VMESSOptions {
     S = 0x01, // default
 
@@ -16,12 +34,123 @@ pub enum VMESSOptions {
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Default)]
 pub enum Encryption {
     AES128CFB = 0x01,
+    /// AES128GCM if the cpu has AES acceleration, CHACHA20POLY1305 otherwise
+    AUTO = 0x02,
     AES128GCM = 0x03,
     CHACHA20POLY1305 = 0x04,
     #[default]
     NONE = 0x05,
 }
 
+/// cipher `AUTO` stands for on this machine, detected once
+static AUTO_SECURITY: LazyLock<Encryption> = LazyLock::new(|| {
+    let resolved = if crypto::has_aes_hardware() {
+        Encryption::AES128GCM
+    } else {
+        Encryption::CHACHA20POLY1305
+    };
+    info!("Security auto resolved to {:?}", resolved);
+    resolved
+});
+
+impl Encryption {
+    /// resolve `AUTO` to the cipher used on this machine, other values are returned as is
+    pub fn resolve(&self) -> Encryption {
+        match self {
+            Encryption::AUTO => AUTO_SECURITY.clone(),
+            encryption => encryption.clone(),
+        }
+    }
+}
+
+/// A VMess user and how its connections are encoded
+#[derive(Clone)]
+pub struct VmessUser {
+    /// id of the uuid, its keys are derived once for all the connections
+    id: aead::ID,
+    /// alter ids of the legacy header, derived from the id, empty with the AEAD header
+    alter_ids: Arc<[aead::ID]>,
+    /// security of the body, never `AUTO` which is resolved when it is set
+    security: Encryption,
+    /// options besides chunk stream (`S`), which is always enabled
+    pub options: Vec<VMESSOptions>,
+    /// clock and rng of the connections
+    pub env: env::Env,
+}
+
+impl VmessUser {
+    /// create a user with `AUTO` security, no extra option, the AEAD header and the system clock
+    pub fn new(uuid: Uuid) -> Self {
+        Self {
+            id: aead::ID::new(uuid),
+            alter_ids: Arc::new([]),
+            security: Encryption::AUTO.resolve(),
+            options: Vec::new(),
+            env: env::Env::default(),
+        }
+    }
+
+    /// use the legacy header with alter_id alter ids, or the AEAD header if it is 0
+    pub fn with_alter_id(mut self, alter_id: u16) -> Self {
+        self.alter_ids = legacy::alter_ids(&self.id, alter_id).into();
+        self
+    }
+
+    /// use security for the body, `AUTO` is resolved to the cipher of this machine
+    pub fn with_security(mut self, security: Encryption) -> Self {
+        self.security = security.resolve();
+        self
+    }
+
+    /// security of the body, resolved
+    pub fn security(&self) -> &Encryption {
+        &self.security
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        self.id.id
+    }
+
+    /// number of alter ids, the legacy header is used if it is not 0
+    pub fn alter_id(&self) -> u16 {
+        self.alter_ids.len() as u16
+    }
+
+    pub(crate) fn id(&self) -> aead::ID {
+        self.id
+    }
+
+    pub(crate) fn alter_ids(&self) -> &[aead::ID] {
+        &self.alter_ids
+    }
+
+    /// option bits of the requests, chunk stream included
+    pub(crate) fn option(&self) -> u8 {
+        self.options
+            .iter()
+            .fold(protocol::RequestOption::ChunkStream as u8, |option, o| {
+                option | *o as u8
+            })
+    }
+
+    /// check that the options can be used together and with the security
+    pub fn validate(&self) -> io::Result<()> {
+        protocol::RequestOption::check(self.option(), (&self.security).try_into()?)
+    }
+}
+
+impl fmt::Debug for VmessUser {
+    /// the uuid and the keys derived from it are not printed
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VmessUser")
+            .field("security", &self.security)
+            .field("options", &self.options)
+            .field("alter_id", &self.alter_id())
+            .field("env", &self.env)
+            .finish_non_exhaustive()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Default)]
 pub enum CMD {
     #[default]
@@ -36,3 +165,107 @@ pub enum AddressType {
     Domain = 0x02,
     IPv6 = 0x03,
 }
+
+/// Destination of a VMess request
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Address {
+    SocketAddr(SocketAddr),
+    DomainName(String, u16),
+}
+
+impl Address {
+    pub fn port(&self) -> u16 {
+        match self {
+            Address::SocketAddr(addr) => addr.port(),
+            Address::DomainName(_, port) => *port,
+        }
+    }
+
+    pub fn address_type(&self) -> AddressType {
+        match self {
+            Address::SocketAddr(SocketAddr::V4(_)) => AddressType::IPv4,
+            Address::SocketAddr(SocketAddr::V6(_)) => AddressType::IPv6,
+            Address::DomainName(..) => AddressType::Domain,
+        }
+    }
+
+    /// write the address as it is in the request header:
+    /// port first, then address type and address
+    pub(crate) fn write_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.port().to_be_bytes());
+        buf.push(self.address_type() as u8);
+        match self {
+            Address::SocketAddr(SocketAddr::V4(addr)) => buf.extend_from_slice(&addr.ip().octets()),
+            Address::SocketAddr(SocketAddr::V6(addr)) => buf.extend_from_slice(&addr.ip().octets()),
+            Address::DomainName(domain, _) => {
+                // domain name is prefixed by its length
+                buf.push(domain.len() as u8);
+                buf.extend_from_slice(domain.as_bytes());
+            }
+        }
+    }
+
+    /// read an address written by `write_to` from the front of buf,
+    /// return the address and the number of bytes read
+    pub(crate) fn read_from(buf: &[u8]) -> io::Result<(Self, usize)> {
+        let too_short = || io::Error::new(io::ErrorKind::InvalidData, "address too short");
+        if buf.len() < 3 {
+            return Err(too_short());
+        }
+        let port = u16::from_be_bytes([buf[0], buf[1]]);
+        match buf[2] {
+            0x01 => {
+                let ip: [u8; 4] = buf.get(3..7).ok_or_else(too_short)?.try_into().unwrap();
+                Ok((Address::SocketAddr((ip, port).into()), 7))
+            }
+            0x02 => {
+                let len = *buf.get(3).ok_or_else(too_short)? as usize;
+                let domain = buf.get(4..4 + len).ok_or_else(too_short)?;
+                let domain = String::from_utf8(domain.to_vec()).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "domain name is not utf-8")
+                })?;
+                Ok((Address::DomainName(domain, port), 4 + len))
+            }
+            0x03 => {
+                let ip: [u8; 16] = buf.get(3..19).ok_or_else(too_short)?.try_into().unwrap();
+                Ok((Address::SocketAddr((ip, port).into()), 19))
+            }
+            address_type => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown address type: {}", address_type),
+            )),
+        }
+    }
+}
+
+impl Display for Address {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::SocketAddr(addr) => write!(f, "{}", addr),
+            Address::DomainName(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_debug_hides_the_credentials() {
+        let uuid = Uuid::parse_str("231c2fc0-f8c4-4248-b098-21f0dd78c810").unwrap();
+        let user = VmessUser::new(uuid)
+            .with_alter_id(2)
+            .with_security(Encryption::AES128GCM);
+        let debug = format!("{:?}", user);
+        assert!(debug.contains("alter_id: 2"), "{}", debug);
+        for secret in [
+            uuid.to_string(),
+            uuid.simple().to_string(),
+            format!("{:?}", user.id().cmd_key),
+            format!("{:?}", user.alter_ids()[0].cmd_key),
+        ] {
+            assert!(!debug.contains(&secret), "{}", debug);
+        }
+    }
+}
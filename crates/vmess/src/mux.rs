@@ -0,0 +1,1109 @@
+//! Mux.Cool client and server
+//!
+//! Many sub-streams share one VMess connection opened with the mux command.
+//! Each frame is the metadata length (u16), the metadata, then the data length (u16)
+//! and the data if the data option is set. The metadata starts with the session id,
+//! the status and the option, a new sub-stream also carries its network and target.
+
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{self, Poll},
+    time::Duration,
+};
+
+use futures::{channel::mpsc, ready, FutureExt, SinkExt, StreamExt};
+use log::{debug, trace, warn};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf},
+    net::UdpSocket,
+    sync::watch,
+    task::JoinHandle,
+};
+
+use crate::{
+    protocol::RequestCommand,
+    server::{connect_tcp, resolve},
+    stream::{VMESSStream, MAX_DATAGRAM_SIZE},
+    Address, VmessUser,
+};
+
+/// max size of the data in one frame of a tcp sub-stream
+const MAX_FRAME_DATA: usize = 1 << 13;
+
+/// number of frames queued for a mux connection, senders wait when it is full
+const FRAME_QUEUE_SIZE: usize = 32;
+
+/// number of frames queued for a sub-stream, the connection stops reading while it is full
+const DATA_QUEUE_SIZE: usize = 16;
+
+/// size of each read from the VMess connection
+const READ_SIZE: usize = 1 << 14;
+
+/// nominal target of the mux request, it is not sent to the server
+const MUX_ADDRESS: &str = "v1.mux.cool";
+
+/// the frame carries data
+const OPTION_DATA: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum SessionStatus {
+    New = 0x01,
+    Keep = 0x02,
+    End = 0x03,
+    KeepAlive = 0x04,
+}
+
+/// Network of a sub-stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Network {
+    Tcp = 0x01,
+    /// each write is sent as one datagram and each read returns one datagram
+    Udp = 0x02,
+}
+
+impl TryFrom<u8> for Network {
+    type Error = io::Error;
+
+    fn try_from(network: u8) -> Result<Self, Self::Error> {
+        match network {
+            0x01 => Ok(Network::Tcp),
+            0x02 => Ok(Network::Udp),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown mux network: {}", network),
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MuxConfig {
+    /// max number of sub-streams on one VMess connection
+    pub concurrency: usize,
+    /// close a VMess connection after it has no sub-stream for this long
+    pub idle_timeout: Duration,
+}
+
+impl Default for MuxConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 8,
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Frame sent to the other side, new sub-streams are only opened by the client
+enum Frame {
+    New {
+        sid: u16,
+        network: Network,
+        target: Address,
+    },
+    Data {
+        sid: u16,
+        data: Vec<u8>,
+    },
+    End {
+        sid: u16,
+    },
+}
+
+impl Frame {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        // placeholder of the metadata length
+        buf.extend_from_slice(&[0, 0]);
+        match self {
+            Frame::New {
+                sid,
+                network,
+                target,
+            } => {
+                buf.extend_from_slice(&sid.to_be_bytes());
+                buf.push(SessionStatus::New as u8);
+                buf.push(0);
+                buf.push(*network as u8);
+                target.write_to(buf);
+            }
+            Frame::Data { sid, .. } => {
+                buf.extend_from_slice(&sid.to_be_bytes());
+                buf.push(SessionStatus::Keep as u8);
+                buf.push(OPTION_DATA);
+            }
+            Frame::End { sid } => {
+                buf.extend_from_slice(&sid.to_be_bytes());
+                buf.push(SessionStatus::End as u8);
+                buf.push(0);
+            }
+        }
+        let metadata_len = (buf.len() - start - 2) as u16;
+        buf[start..start + 2].copy_from_slice(&metadata_len.to_be_bytes());
+
+        if let Frame::Data { data, .. } = self {
+            buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            buf.extend_from_slice(data);
+        }
+    }
+}
+
+/// Frame received from the other side, the rest of the metadata is ignored
+#[derive(Debug)]
+struct IncomingFrame {
+    sid: u16,
+    status: u8,
+    /// network and target of a new sub-stream
+    new: Option<(Network, Address)>,
+    data: Option<Vec<u8>>,
+}
+
+impl IncomingFrame {
+    /// decode one frame from the front of src and remove it from src,
+    /// return None if src does not hold a complete frame yet
+    fn decode(src: &mut Vec<u8>) -> io::Result<Option<Self>> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+        let metadata_len = u16::from_be_bytes([src[0], src[1]]) as usize;
+        if metadata_len < 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid mux metadata length {}", metadata_len),
+            ));
+        }
+        let mut end = 2 + metadata_len;
+        if src.len() < end {
+            return Ok(None);
+        }
+        let sid = u16::from_be_bytes([src[2], src[3]]);
+        let status = src[4];
+        let option = src[5];
+        let new = if status == SessionStatus::New as u8 {
+            if metadata_len < 5 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "mux new frame without network",
+                ));
+            }
+            let network = Network::try_from(src[6])?;
+            let (target, _) = Address::read_from(&src[7..end])?;
+            Some((network, target))
+        } else {
+            None
+        };
+
+        let data = if option & OPTION_DATA != 0 {
+            if src.len() < end + 2 {
+                return Ok(None);
+            }
+            let data_len = u16::from_be_bytes([src[end], src[end + 1]]) as usize;
+            if src.len() < end + 2 + data_len {
+                return Ok(None);
+            }
+            let data = src[end + 2..end + 2 + data_len].to_vec();
+            end += 2 + data_len;
+            Some(data)
+        } else {
+            None
+        };
+        src.drain(..end);
+        Ok(Some(IncomingFrame {
+            sid,
+            status,
+            new,
+            data,
+        }))
+    }
+}
+
+/// Sub-streams of a mux connection
+#[derive(Debug, Default)]
+struct MuxState {
+    /// data from the server is sent to the sub-stream through its sender
+    sessions: HashMap<u16, mpsc::Sender<Vec<u8>>>,
+    next_sid: u16,
+    /// no more sub-stream can be opened
+    closed: bool,
+}
+
+impl MuxState {
+    fn allocate_sid(&mut self) -> u16 {
+        loop {
+            // 0 is not used as a session id
+            self.next_sid = self.next_sid.wrapping_add(1).max(1);
+            if !self.sessions.contains_key(&self.next_sid) {
+                return self.next_sid;
+            }
+        }
+    }
+}
+
+/// One VMess connection carrying sub-streams, served by a writer task and a reader task
+struct MuxConnection {
+    state: Arc<Mutex<MuxState>>,
+    frames: mpsc::Sender<Frame>,
+    local_addr: SocketAddr,
+}
+
+impl MuxConnection {
+    fn new(stream: VMESSStream, idle_timeout: Duration) -> io::Result<Self> {
+        let local_addr = stream.local_addr()?;
+        let state = Arc::new(Mutex::new(MuxState::default()));
+        let (frames, frames_rx) = mpsc::channel(FRAME_QUEUE_SIZE);
+        let (reader, writer) = tokio::io::split(stream);
+
+        let writer_state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = write_frames(writer, frames_rx, &writer_state, idle_timeout).await {
+                warn!("Mux connection write error: {}", e);
+            }
+            writer_state.lock().unwrap().closed = true;
+        });
+        let reader_state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = read_frames(reader, &reader_state).await {
+                warn!("Mux connection read error: {}", e);
+            }
+            let mut state = reader_state.lock().unwrap();
+            state.closed = true;
+            // end the reads of all sub-streams
+            state.sessions.clear();
+        });
+
+        Ok(Self {
+            state,
+            frames,
+            local_addr,
+        })
+    }
+
+    fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// register a new sub-stream if the connection is open and not full
+    fn open(&self, concurrency: usize) -> Option<(u16, mpsc::Receiver<Vec<u8>>)> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed || state.sessions.len() >= concurrency {
+            return None;
+        }
+        let sid = state.allocate_sid();
+        let (sender, receiver) = mpsc::channel(DATA_QUEUE_SIZE);
+        state.sessions.insert(sid, sender);
+        Some((sid, receiver))
+    }
+}
+
+/// encode the queued frames and write them to the VMess connection,
+/// shut it down after idle_timeout without any frame or sub-stream
+async fn write_frames(
+    mut writer: WriteHalf<VMESSStream>,
+    mut frames: mpsc::Receiver<Frame>,
+    state: &Mutex<MuxState>,
+    idle_timeout: Duration,
+) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(READ_SIZE);
+    loop {
+        let frame = match tokio::time::timeout(idle_timeout, frames.next()).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(_) => {
+                let mut state = state.lock().unwrap();
+                if state.sessions.is_empty() {
+                    debug!("Close idle mux connection");
+                    state.closed = true;
+                    break;
+                }
+                continue;
+            }
+        };
+        buf.clear();
+        frame.encode(&mut buf);
+        // write the frames already queued together
+        while let Some(Some(frame)) = frames.next().now_or_never() {
+            frame.encode(&mut buf);
+        }
+        writer.write_all(&buf).await?;
+        writer.flush().await?;
+    }
+    writer.shutdown().await
+}
+
+/// decode frames from the VMess connection and dispatch them to the sub-streams,
+/// reading stops while the queue of a sub-stream is full
+async fn read_frames(mut reader: ReadHalf<VMESSStream>, state: &Mutex<MuxState>) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(READ_SIZE);
+    let mut read_buf = vec![0u8; READ_SIZE];
+    loop {
+        while let Some(frame) = IncomingFrame::decode(&mut buf)? {
+            trace!("Mux frame: sid {}, status {}", frame.sid, frame.status);
+            match frame.status {
+                s if s == SessionStatus::Keep as u8 || s == SessionStatus::New as u8 => {
+                    let data = match frame.data {
+                        Some(data) if !data.is_empty() => data,
+                        _ => continue,
+                    };
+                    // the lock must not be held while waiting for the sub-stream
+                    let sender = state.lock().unwrap().sessions.get(&frame.sid).cloned();
+                    match sender {
+                        Some(mut sender) => queue_data(&mut sender, frame.sid, data).await,
+                        None => trace!("Unknown sub-stream {}, drop its data", frame.sid),
+                    }
+                }
+                s if s == SessionStatus::End as u8 => {
+                    state.lock().unwrap().sessions.remove(&frame.sid);
+                }
+                s if s == SessionStatus::KeepAlive as u8 => {}
+                s => warn!("Unknown mux session status {}", s),
+            }
+        }
+        let n = reader.read(&mut read_buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&read_buf[..n]);
+    }
+}
+
+/// queue data for a sub-stream, wait until it has room,
+/// the data is dropped if the sub-stream is already closed
+async fn queue_data(sender: &mut mpsc::Sender<Vec<u8>>, sid: u16, data: Vec<u8>) {
+    if sender.send(data).await.is_err() {
+        trace!("Sub-stream {} is closed, drop its data", sid);
+    }
+}
+
+/// Opens sub-streams on a pool of VMess connections to one server
+pub struct MuxClient {
+    addr: String,
+    user: VmessUser,
+    config: MuxConfig,
+    connections: Mutex<Vec<Arc<MuxConnection>>>,
+}
+
+impl MuxClient {
+    /// create a client connecting to the VMess server at addr as user,
+    /// connections are opened when they are needed
+    pub fn new(addr: impl Into<String>, user: VmessUser, config: MuxConfig) -> Self {
+        Self {
+            addr: addr.into(),
+            user,
+            config: MuxConfig {
+                concurrency: config.concurrency.max(1),
+                ..config
+            },
+            connections: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// open a tcp sub-stream to target
+    pub async fn connect(&self, target: Address) -> io::Result<MuxStream> {
+        self.open(Network::Tcp, target).await
+    }
+
+    /// open a udp sub-stream to target
+    pub async fn connect_udp(&self, target: Address) -> io::Result<MuxStream> {
+        self.open(Network::Udp, target).await
+    }
+
+    async fn open(&self, network: Network, target: Address) -> io::Result<MuxStream> {
+        let opened = {
+            let mut connections = self.connections.lock().unwrap();
+            connections.retain(|connection| !connection.is_closed());
+            connections.iter().find_map(|connection| {
+                connection
+                    .open(self.config.concurrency)
+                    .map(|(sid, data)| (connection.clone(), sid, data))
+            })
+        };
+        let (connection, sid, data) = match opened {
+            Some(opened) => opened,
+            None => {
+                let stream = VMESSStream::connect_command(
+                    self.addr.as_str(),
+                    RequestCommand::Mux,
+                    Address::DomainName(MUX_ADDRESS.to_string(), 0),
+                    &self.user,
+                )
+                .await?;
+                let connection = Arc::new(MuxConnection::new(stream, self.config.idle_timeout)?);
+                let (sid, data) = connection
+                    .open(self.config.concurrency)
+                    .ok_or_else(closed_error)?;
+                self.connections.lock().unwrap().push(connection.clone());
+                (connection, sid, data)
+            }
+        };
+        debug!("Open mux sub-stream {} to {}", sid, target);
+
+        let mut frames = connection.frames.clone();
+        frames
+            .send(Frame::New {
+                sid,
+                network,
+                target,
+            })
+            .await
+            .map_err(|_| closed_error())?;
+        Ok(MuxStream {
+            sid,
+            network,
+            frames,
+            data,
+            plain: Vec::new(),
+            plain_pos: 0,
+            state: connection.state.clone(),
+            local_addr: connection.local_addr,
+            write_closed: false,
+        })
+    }
+}
+
+impl fmt::Debug for MuxClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MuxClient")
+            .field("addr", &self.addr)
+            .field("user", &self.user)
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+fn closed_error() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "mux connection is closed")
+}
+
+/// A logical stream carried by a mux connection
+pub struct MuxStream {
+    sid: u16,
+    network: Network,
+    frames: mpsc::Sender<Frame>,
+    data: mpsc::Receiver<Vec<u8>>,
+    /// received data not yet returned to the caller
+    plain: Vec<u8>,
+    plain_pos: usize,
+    state: Arc<Mutex<MuxState>>,
+    local_addr: SocketAddr,
+    /// the end frame has been sent
+    write_closed: bool,
+}
+
+impl MuxStream {
+    /// local address of the underlying VMess connection
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        MAX_FRAME_DATA
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.write_closed {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "write after shutdown",
+            )));
+        }
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let n = match this.network {
+            Network::Tcp => buf.len().min(MAX_FRAME_DATA),
+            Network::Udp if buf.len() > u16::MAX as usize => {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("datagram too large: {}", buf.len()),
+                )));
+            }
+            Network::Udp => buf.len(),
+        };
+        ready!(this.frames.poll_ready(cx)).map_err(|_| closed_error())?;
+        this.frames
+            .start_send(Frame::Data {
+                sid: this.sid,
+                data: buf[..n].to_vec(),
+            })
+            .map_err(|_| closed_error())?;
+        Poll::Ready(Ok(n))
+    }
+
+    /// frames are flushed by the mux connection as soon as they are queued
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.write_closed {
+            ready!(this.frames.poll_ready(cx)).map_err(|_| closed_error())?;
+            this.frames
+                .start_send(Frame::End { sid: this.sid })
+                .map_err(|_| closed_error())?;
+            this.write_closed = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.plain_pos < this.plain.len() {
+                let n = buf.remaining().min(this.plain.len() - this.plain_pos);
+                buf.put_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+                this.plain_pos += n;
+                if this.network == Network::Udp {
+                    // like a udp socket, the rest of a datagram that does not fit is discarded
+                    this.plain_pos = this.plain.len();
+                }
+                return Poll::Ready(Ok(()));
+            }
+            match ready!(this.data.poll_next_unpin(cx)) {
+                Some(data) => {
+                    this.plain = data;
+                    this.plain_pos = 0;
+                }
+                // the server ended the sub-stream or the connection is closed
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().sessions.remove(&self.sid);
+        if !self.write_closed && self.frames.try_send(Frame::End { sid: self.sid }).is_err() {
+            debug!("Failed to end mux sub-stream {}", self.sid);
+        }
+    }
+}
+
+/// Sub-stream served by `serve`
+struct ServerSession {
+    /// data from the client is sent to the task of the sub-stream through the sender
+    data: mpsc::Sender<Vec<u8>>,
+    task: JoinHandle<()>,
+}
+
+/// serve the sub-streams of a mux request accepted by the server, their targets are
+/// connected directly, return when the client closes the VMess connection
+pub(crate) async fn serve(stream: VMESSStream) -> io::Result<()> {
+    let (reader, writer) = tokio::io::split(stream);
+    let (frames, frames_rx) = mpsc::channel(FRAME_QUEUE_SIZE);
+    // the sub-streams still running end when the sender is dropped
+    let (closed, closed_rx) = watch::channel(());
+    let writer = tokio::spawn(write_server_frames(writer, frames_rx));
+    let mut sessions = HashMap::new();
+    let result = serve_frames(reader, &mut sessions, frames, closed_rx).await;
+    for session in sessions.into_values() {
+        session.task.abort();
+    }
+    drop(closed);
+    result.and(writer.await?)
+}
+
+/// decode the frames of the client, open the sub-streams and dispatch their data
+async fn serve_frames(
+    mut reader: ReadHalf<VMESSStream>,
+    sessions: &mut HashMap<u16, ServerSession>,
+    frames: mpsc::Sender<Frame>,
+    closed: watch::Receiver<()>,
+) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(READ_SIZE);
+    let mut read_buf = vec![0u8; READ_SIZE];
+    loop {
+        while let Some(frame) = IncomingFrame::decode(&mut buf)? {
+            trace!("Mux frame: sid {}, status {}", frame.sid, frame.status);
+            let sid = frame.sid;
+            match frame.status {
+                s if s == SessionStatus::New as u8 => {
+                    let (network, target) = frame.new.expect("new frames have a target");
+                    debug!("Mux sub-stream {} opens {:?} to {}", sid, network, target);
+                    let (data, data_rx) = mpsc::channel(DATA_QUEUE_SIZE);
+                    let mut closed = closed.clone();
+                    let session_frames = frames.clone();
+                    let task = tokio::spawn(async move {
+                        tokio::select! {
+                            _ = serve_session(sid, network, target, data_rx, session_frames) => {}
+                            _ = closed.changed() => {}
+                        }
+                    });
+                    if let Some(previous) = sessions.insert(sid, ServerSession { data, task }) {
+                        previous.task.abort();
+                    }
+                }
+                s if s == SessionStatus::Keep as u8 => {}
+                s if s == SessionStatus::End as u8 => {
+                    // the data sender is dropped, the target is shut down once its queue is drained
+                    sessions.remove(&sid);
+                    continue;
+                }
+                s if s == SessionStatus::KeepAlive as u8 => continue,
+                s => {
+                    warn!("Unknown mux session status {}", s);
+                    continue;
+                }
+            }
+
+            let data = match frame.data {
+                Some(data) if !data.is_empty() => data,
+                _ => continue,
+            };
+            match sessions.get_mut(&sid) {
+                Some(session) => queue_data(&mut session.data, sid, data).await,
+                None => trace!("Unknown sub-stream {}, drop its data", sid),
+            }
+        }
+        let n = reader.read(&mut read_buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&read_buf[..n]);
+    }
+}
+
+/// relay one sub-stream to its target, end it when the target is done
+async fn serve_session(
+    sid: u16,
+    network: Network,
+    target: Address,
+    mut data: mpsc::Receiver<Vec<u8>>,
+    mut frames: mpsc::Sender<Frame>,
+) {
+    let result = match network {
+        Network::Tcp => serve_tcp(sid, &target, &mut data, &mut frames).await,
+        Network::Udp => serve_udp(sid, &target, &mut data, &mut frames).await,
+    };
+    match result {
+        // the client ended the sub-stream
+        Ok(false) => {
+            debug!("Mux sub-stream {} to {} closed", sid, target);
+            return;
+        }
+        Ok(true) => debug!("Mux sub-stream {} to {} closed by the target", sid, target),
+        Err(e) => debug!("Mux sub-stream {} to {} failed: {}", sid, target, e),
+    }
+    if frames.send(Frame::End { sid }).await.is_err() {
+        trace!("Mux connection closed before sub-stream {} ended", sid);
+    }
+}
+
+/// relay a tcp sub-stream, return whether it should be ended by the server:
+/// the target can still send data after the client has ended the sub-stream
+async fn serve_tcp(
+    sid: u16,
+    target: &Address,
+    data: &mut mpsc::Receiver<Vec<u8>>,
+    frames: &mut mpsc::Sender<Frame>,
+) -> io::Result<bool> {
+    let stream = connect_tcp(target).await?;
+    let (mut target_reader, mut target_writer) = stream.into_split();
+    let uplink = async {
+        while let Some(data) = data.next().await {
+            target_writer.write_all(&data).await?;
+        }
+        target_writer.shutdown().await
+    };
+    let downlink = async {
+        let mut buf = vec![0u8; MAX_FRAME_DATA];
+        loop {
+            let n = target_reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            let frame = Frame::Data {
+                sid,
+                data: buf[..n].to_vec(),
+            };
+            frames.send(frame).await.map_err(|_| closed_error())?;
+        }
+    };
+    // the sub-stream ends with the downlink, the uplink is only waited for while it runs
+    tokio::pin!(uplink, downlink);
+    let mut uplink_done = false;
+    loop {
+        tokio::select! {
+            result = &mut uplink, if !uplink_done => {
+                result?;
+                uplink_done = true;
+            }
+            result = &mut downlink => return result.map(|()| true),
+        }
+    }
+}
+
+/// relay a udp sub-stream, each frame carries one datagram,
+/// return whether it should be ended by the server, which is never the case without error
+async fn serve_udp(
+    sid: u16,
+    target: &Address,
+    data: &mut mpsc::Receiver<Vec<u8>>,
+    frames: &mut mpsc::Sender<Frame>,
+) -> io::Result<bool> {
+    let target = resolve(target).await?;
+    let bind_addr: SocketAddr = if target.is_ipv4() {
+        ([0u8; 4], 0).into()
+    } else {
+        ([0u8; 16], 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(target).await?;
+    let uplink = async {
+        while let Some(datagram) = data.next().await {
+            socket.send(&datagram).await?;
+        }
+        Ok::<_, io::Error>(())
+    };
+    let downlink = async {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            let frame = Frame::Data {
+                sid,
+                data: buf[..n].to_vec(),
+            };
+            frames.send(frame).await.map_err(|_| closed_error())?;
+        }
+    };
+    tokio::select! {
+        result = uplink => result.map(|()| false),
+        result = downlink => result,
+    }
+}
+
+/// encode the frames of the sub-streams and write them to the VMess connection,
+/// shut it down once every sub-stream is done
+async fn write_server_frames(
+    mut writer: WriteHalf<VMESSStream>,
+    mut frames: mpsc::Receiver<Frame>,
+) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(READ_SIZE);
+    while let Some(frame) = frames.next().await {
+        buf.clear();
+        frame.encode(&mut buf);
+        // write the frames already queued together
+        while let Some(Some(frame)) = frames.next().now_or_never() {
+            frame.encode(&mut buf);
+        }
+        writer.write_all(&buf).await?;
+        writer.flush().await?;
+    }
+    writer.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use tokio::net::TcpListener;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::VmessServer;
+
+    fn decode_one(buf: &mut Vec<u8>) -> IncomingFrame {
+        let frame = IncomingFrame::decode(buf).unwrap().unwrap();
+        assert!(buf.is_empty());
+        frame
+    }
+
+    #[test]
+    fn new_frame() {
+        let target = Address::DomainName("example.com".to_string(), 443);
+        let mut buf = Vec::new();
+        Frame::New {
+            sid: 1,
+            network: Network::Tcp,
+            target: target.clone(),
+        }
+        .encode(&mut buf);
+        assert_eq!(
+            buf[..7],
+            [0x00, 0x14, 0x00, 0x01, SessionStatus::New as u8, 0x00, 0x01]
+        );
+        let frame = decode_one(&mut buf);
+        assert_eq!(frame.sid, 1);
+        assert_eq!(frame.status, SessionStatus::New as u8);
+        assert_eq!(frame.new, Some((Network::Tcp, target)));
+        assert_eq!(frame.data, None);
+
+        let target = Address::SocketAddr(([8, 8, 8, 8], 53).into());
+        Frame::New {
+            sid: 0x1234,
+            network: Network::Udp,
+            target: target.clone(),
+        }
+        .encode(&mut buf);
+        let frame = decode_one(&mut buf);
+        assert_eq!(frame.sid, 0x1234);
+        assert_eq!(frame.new, Some((Network::Udp, target)));
+        assert_eq!(frame.data, None);
+    }
+
+    #[test]
+    fn new_frame_with_data() {
+        let target = Address::SocketAddr(([127, 0, 0, 1], 80).into());
+        let mut buf = Vec::new();
+        Frame::New {
+            sid: 2,
+            network: Network::Tcp,
+            target: target.clone(),
+        }
+        .encode(&mut buf);
+        // the client does not send data with the new frame, other implementations do
+        buf[5] = OPTION_DATA;
+        buf.extend_from_slice(&[0x00, 0x03, b'a', b'b', b'c']);
+        let frame = decode_one(&mut buf);
+        assert_eq!(frame.new, Some((Network::Tcp, target)));
+        assert_eq!(frame.data.as_deref(), Some(&b"abc"[..]));
+    }
+
+    #[test]
+    fn keep_frame() {
+        let mut buf = Vec::new();
+        Frame::Data {
+            sid: 3,
+            data: b"data".to_vec(),
+        }
+        .encode(&mut buf);
+        assert_eq!(
+            buf,
+            [
+                0x00,
+                0x04,
+                0x00,
+                0x03,
+                0x02,
+                OPTION_DATA,
+                0x00,
+                0x04,
+                b'd',
+                b'a',
+                b't',
+                b'a'
+            ]
+        );
+        let frame = decode_one(&mut buf);
+        assert_eq!(frame.sid, 3);
+        assert_eq!(frame.status, SessionStatus::Keep as u8);
+        assert_eq!(frame.new, None);
+        assert_eq!(frame.data.as_deref(), Some(&b"data"[..]));
+
+        // without data
+        let mut buf = vec![0x00, 0x04, 0x00, 0x03, 0x02, 0x00];
+        let frame = decode_one(&mut buf);
+        assert_eq!(frame.status, SessionStatus::Keep as u8);
+        assert_eq!(frame.data, None);
+    }
+
+    #[test]
+    fn end_frame() {
+        let mut buf = Vec::new();
+        Frame::End { sid: 4 }.encode(&mut buf);
+        assert_eq!(buf, [0x00, 0x04, 0x00, 0x04, 0x03, 0x00]);
+        let frame = decode_one(&mut buf);
+        assert_eq!(frame.sid, 4);
+        assert_eq!(frame.status, SessionStatus::End as u8);
+        assert_eq!(frame.new, None);
+        assert_eq!(frame.data, None);
+    }
+
+    #[test]
+    fn keep_alive_frame() {
+        let mut buf = vec![0x00, 0x04, 0x00, 0x00, 0x04, 0x00];
+        let frame = decode_one(&mut buf);
+        assert_eq!(frame.status, SessionStatus::KeepAlive as u8);
+        assert_eq!(frame.data, None);
+
+        // its data is consumed with the frame
+        let mut buf = vec![
+            0x00,
+            0x04,
+            0x00,
+            0x00,
+            0x04,
+            OPTION_DATA,
+            0x00,
+            0x02,
+            0xff,
+            0xff,
+        ];
+        let frame = decode_one(&mut buf);
+        assert_eq!(frame.status, SessionStatus::KeepAlive as u8);
+        assert_eq!(frame.data.as_deref(), Some(&[0xff, 0xff][..]));
+    }
+
+    #[test]
+    fn partial_frames() {
+        let mut encoded = Vec::new();
+        Frame::Data {
+            sid: 5,
+            data: b"first".to_vec(),
+        }
+        .encode(&mut encoded);
+        Frame::End { sid: 5 }.encode(&mut encoded);
+
+        // nothing is consumed until a frame is complete
+        for len in 0..11 {
+            let mut buf = encoded[..len].to_vec();
+            assert!(IncomingFrame::decode(&mut buf).unwrap().is_none());
+            assert_eq!(buf.len(), len);
+        }
+        let mut buf = encoded;
+        let frame = IncomingFrame::decode(&mut buf).unwrap().unwrap();
+        assert_eq!(frame.data.as_deref(), Some(&b"first"[..]));
+        let frame = decode_one(&mut buf);
+        assert_eq!(frame.status, SessionStatus::End as u8);
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let mut buf = vec![0x00, 0x03, 0x00, 0x01, 0x02];
+        let e = IncomingFrame::decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        let mut buf = vec![0x00, 0x05, 0x00, 0x01, 0x01, 0x00, 0x03];
+        let e = IncomingFrame::decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// echo every connection accepted by listener
+    async fn echo(listener: TcpListener) {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.into_split();
+                tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+                writer.shutdown().await.unwrap();
+            });
+        }
+    }
+
+    /// a mux client of a local server, sub-streams to the echo target
+    async fn mux_client(config: MuxConfig) -> (MuxClient, Address) {
+        let uuid = Uuid::from_u128(0x6d75_7878);
+        let mut server = VmessServer::new("127.0.0.1:0", &[uuid]).await.unwrap();
+        let server_addr = server.listener.local_addr().unwrap();
+        tokio::spawn(async move { server.serve().await });
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = Address::SocketAddr(target.local_addr().unwrap());
+        tokio::spawn(echo(target));
+        let client = MuxClient::new(server_addr.to_string(), VmessUser::new(uuid), config);
+        (client, target_addr)
+    }
+
+    async fn echo_round(stream: &mut MuxStream, data: &[u8]) {
+        stream.write_all(data).await.unwrap();
+        let mut buf = vec![0u8; data.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[tokio::test]
+    async fn concurrent_sub_streams() {
+        let config = MuxConfig {
+            concurrency: 3,
+            idle_timeout: Duration::from_millis(200),
+        };
+        let (client, target) = mux_client(config).await;
+        let mut streams = Vec::new();
+        for _ in 0..3 {
+            streams.push(client.connect(target.clone()).await.unwrap());
+        }
+        let sids: HashSet<u16> = streams.iter().map(|stream| stream.sid).collect();
+        assert_eq!(sids.len(), 3);
+        assert_eq!(client.connections.lock().unwrap().len(), 1);
+
+        let rounds = streams
+            .iter_mut()
+            .enumerate()
+            .map(|(i, stream)| async move {
+                for round in 0..4u8 {
+                    echo_round(stream, &vec![i as u8 ^ round; 1000 * (i + 1)]).await;
+                }
+            });
+        futures::future::join_all(rounds).await;
+
+        // the server ends the closed sub-stream once the target is done
+        let mut closed = streams.remove(1);
+        closed.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        closed.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+        drop(closed);
+        for stream in streams.iter_mut() {
+            echo_round(stream, b"still open").await;
+        }
+
+        // a fourth sub-stream takes the place of the closed one on the same connection
+        let mut stream = client.connect(target.clone()).await.unwrap();
+        echo_round(&mut stream, b"reused").await;
+        streams.push(stream);
+        assert_eq!(client.connections.lock().unwrap().len(), 1);
+
+        // the connection is kept while it has sub-streams
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let connection = client.connections.lock().unwrap()[0].clone();
+        assert!(!connection.is_closed());
+
+        drop(streams);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(connection.is_closed());
+
+        // a new connection is opened for the next sub-stream
+        let mut stream = client.connect(target).await.unwrap();
+        echo_round(&mut stream, b"new connection").await;
+        let connections = client.connections.lock().unwrap();
+        assert_eq!(connections.len(), 1);
+        assert!(!Arc::ptr_eq(&connections[0], &connection));
+    }
+
+    #[tokio::test]
+    async fn slow_sub_stream_keeps_its_data() {
+        let (client, target) = mux_client(MuxConfig::default()).await;
+        let stream = client.connect(target).await.unwrap();
+        let (mut reader, mut writer) = tokio::io::split(stream);
+
+        // much more than the queue of the sub-stream
+        let data: Vec<u8> = (0..DATA_QUEUE_SIZE * MAX_FRAME_DATA * 4)
+            .map(|i| i as u8)
+            .collect();
+        let expected = data.clone();
+        let write = tokio::spawn(async move {
+            writer.write_all(&data).await.unwrap();
+            writer.shutdown().await.unwrap();
+            writer
+        });
+        // the queue fills up while the sub-stream is not read
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), expected.len());
+        assert!(received == expected);
+        write.await.unwrap();
+    }
+}
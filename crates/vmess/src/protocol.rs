@@ -1,3 +1,10 @@
+use std::io;
+
+use log::warn;
+use uuid::Uuid;
+
+use crate::{crypto::fnv::fnv, Address, Encryption};
+
 pub(crate) const VERSION: u8 = 1;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
@@ -10,6 +17,22 @@ pub(crate) enum RequestCommand {
     Mux = 0x03,
 }
 
+impl TryFrom<u8> for RequestCommand {
+    type Error = io::Error;
+
+    fn try_from(command: u8) -> Result<Self, Self::Error> {
+        match command {
+            0x01 => Ok(RequestCommand::Tcp),
+            0x02 => Ok(RequestCommand::Udp),
+            0x03 => Ok(RequestCommand::Mux),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown request command: {}", command),
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 #[repr(u8)]
 #[allow(dead_code)]
@@ -23,6 +46,34 @@ pub(crate) enum RequestOption {
     AuthenticatedLength = 0x10,
 }
 
+impl RequestOption {
+    /// whether this option is set in the option bits
+    pub(crate) fn is_set(self, option: u8) -> bool {
+        option & self as u8 != 0
+    }
+
+    /// refuse the combinations of options, and of options and security, that can't be encoded
+    pub(crate) fn check(option: u8, security: RequestSecurity) -> io::Result<()> {
+        // the length of the padding comes from the mask
+        if RequestOption::GlobalPadding.is_set(option)
+            && !RequestOption::ChunkMasking.is_set(option)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid option: RequestOptionGlobalPadding",
+            ));
+        }
+        // the length is sealed with the cipher of the data
+        if RequestOption::AuthenticatedLength.is_set(option) && security == RequestSecurity::None {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid option: RequestOptionAuthenticatedLength requires an encrypted security",
+            ));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 #[repr(u8)]
 #[allow(dead_code)]
@@ -37,26 +88,306 @@ pub(crate) enum RequestSecurity {
     Zero = 6,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+impl TryFrom<&Encryption> for RequestSecurity {
+    type Error = io::Error;
+
+    fn try_from(encryption: &Encryption) -> Result<Self, Self::Error> {
+        match encryption {
+            Encryption::AES128GCM => Ok(RequestSecurity::AES128GCM),
+            Encryption::CHACHA20POLY1305 => Ok(RequestSecurity::CHACHA20POLY1305),
+            Encryption::NONE => Ok(RequestSecurity::None),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Unsupported security: {:?}", encryption),
+            )),
+        }
+    }
+}
+
+impl TryFrom<u8> for RequestSecurity {
+    type Error = io::Error;
+
+    fn try_from(security: u8) -> Result<Self, Self::Error> {
+        match security {
+            3 => Ok(RequestSecurity::AES128GCM),
+            4 => Ok(RequestSecurity::CHACHA20POLY1305),
+            5 => Ok(RequestSecurity::None),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Unsupported security: {}", security),
+            )),
+        }
+    }
+}
+
+impl From<RequestSecurity> for Encryption {
+    fn from(security: RequestSecurity) -> Self {
+        match security {
+            RequestSecurity::Legacy => Encryption::AES128CFB,
+            RequestSecurity::Auto => Encryption::AUTO,
+            RequestSecurity::AES128GCM => Encryption::AES128GCM,
+            RequestSecurity::CHACHA20POLY1305 => Encryption::CHACHA20POLY1305,
+            RequestSecurity::Unknown | RequestSecurity::None | RequestSecurity::Zero => {
+                Encryption::NONE
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub(crate) struct RequestHeader {
     pub(crate) version: u8,
     pub(crate) command: RequestCommand,
-    pub(crate) option: RequestOption,
+    /// bits of `RequestOption`
+    pub(crate) option: u8,
     pub(crate) security: RequestSecurity,
-    pub(crate) port: u16,
-    pub(crate) address: [u8; 16],
+    pub(crate) address: Address,
 }
 
 impl RequestHeader {
-    fn encode(&self, buf: &[u8]) -> Vec<u8> {
-        let mut v = Vec::with_capacity(32);
+    /// encode the command section, the padding (at most 15 bytes) is put before the fnv hash
+    pub(crate) fn encode(
+        &self,
+        body_iv: &[u8; 16],
+        body_key: &[u8; 16],
+        response_header: u8,
+        padding: &[u8],
+    ) -> Vec<u8> {
+        let mut v = Vec::with_capacity(64);
         v.push(self.version);
+        v.extend_from_slice(body_iv);
+        v.extend_from_slice(body_key);
+        v.push(response_header);
+        v.push(self.option);
+        v.push((padding.len() as u8) << 4 | self.security as u8);
+        // reserved
+        v.push(0);
         v.push(self.command as u8);
-        v.push(self.option as u8);
-        v.push(self.security as u8);
-        v.extend_from_slice(&self.port.to_be_bytes());
-        v.extend_from_slice(&self.address);
-        v.extend_from_slice(buf);
+        // the targets of mux sub-streams are sent in the frames instead
+        if self.command != RequestCommand::Mux {
+            self.address.write_to(&mut v);
+        }
+        v.extend_from_slice(padding);
+
+        let fnv_hash = fnv(&v);
+        v.extend_from_slice(&fnv_hash.to_be_bytes());
         v
     }
+
+    /// decode the command section, return the header, the body iv, the body key
+    /// and the response header byte
+    pub(crate) fn decode(buf: &[u8]) -> io::Result<(Self, [u8; 16], [u8; 16], u8)> {
+        let too_short = || io::Error::new(io::ErrorKind::InvalidData, "request header too short");
+        // version, iv, key, response header, option, padding and security, reserved, command
+        if buf.len() < 38 + 4 {
+            return Err(too_short());
+        }
+        let (data, fnv_hash) = buf.split_at(buf.len() - 4);
+        if fnv(data).to_be_bytes() != fnv_hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request header checksum mismatch",
+            ));
+        }
+
+        let version = data[0];
+        let body_iv: [u8; 16] = data[1..17].try_into().expect("length is 16");
+        let body_key: [u8; 16] = data[17..33].try_into().expect("length is 16");
+        let response_header = data[33];
+        let option = data[34];
+        let padding_len = (data[35] >> 4) as usize;
+        let security = RequestSecurity::try_from(data[35] & 0x0F)?;
+        let command = RequestCommand::try_from(data[37])?;
+
+        let mut pos = 38;
+        // mux requests carry no address
+        let address = if command == RequestCommand::Mux {
+            Address::DomainName(String::new(), 0)
+        } else {
+            let (address, len) = Address::read_from(&data[pos..])?;
+            pos += len;
+            address
+        };
+        if data.len() != pos + padding_len {
+            return Err(too_short());
+        }
+
+        let header = RequestHeader {
+            version,
+            command,
+            option,
+            security,
+            address,
+        };
+        Ok((header, body_iv, body_key, response_header))
+    }
+}
+
+/// Command sent by the server in the response header
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(dead_code)]
+pub(crate) enum ResponseCommand {
+    /// dynamic port, ask the client to use another account
+    SwitchAccount {
+        host: String,
+        port: u16,
+        id: Uuid,
+        alter_id: u16,
+        level: u8,
+        valid_minutes: u8,
+    },
+    Unknown(u8, Vec<u8>),
+}
+
+impl ResponseCommand {
+    fn decode(command: u8, data: &[u8]) -> io::Result<Self> {
+        match command {
+            0x01 => {
+                let invalid =
+                    || io::Error::new(io::ErrorKind::InvalidData, "invalid switch account command");
+                let host_len = *data.first().ok_or_else(invalid)? as usize;
+                let data = &data[1..];
+                if data.len() < host_len + 2 + 16 + 2 + 1 + 1 {
+                    return Err(invalid());
+                }
+                let host = String::from_utf8_lossy(&data[..host_len]).to_string();
+                let data = &data[host_len..];
+                Ok(ResponseCommand::SwitchAccount {
+                    host,
+                    port: u16::from_be_bytes([data[0], data[1]]),
+                    id: Uuid::from_slice(&data[2..18]).map_err(|_| invalid())?,
+                    alter_id: u16::from_be_bytes([data[18], data[19]]),
+                    level: data[20],
+                    valid_minutes: data[21],
+                })
+            }
+            _ => Ok(ResponseCommand::Unknown(command, data.to_vec())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ResponseHeader {
+    /// should be the same as the one in the request
+    pub(crate) response_header: u8,
+    pub(crate) option: u8,
+    pub(crate) command: Option<ResponseCommand>,
+}
+
+impl ResponseHeader {
+    /// encode the response header, commands are not supported and never encoded
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        vec![self.response_header, self.option, 0, 0]
+    }
+
+    pub(crate) fn decode(buf: &[u8]) -> io::Result<Self> {
+        if buf.len() < 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "response header too short",
+            ));
+        }
+        let command = match buf[2] {
+            0 => None,
+            command => {
+                let len = buf[3] as usize;
+                let data = buf.get(4..4 + len).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "response command too short")
+                })?;
+                match ResponseCommand::decode(command, data) {
+                    Ok(command) => Some(command),
+                    Err(e) => {
+                        warn!("Ignore response command {}: {}", command, e);
+                        None
+                    }
+                }
+            }
+        };
+        Ok(ResponseHeader {
+            response_header: buf[0],
+            option: buf[1],
+            command,
+        })
+    }
+
+    /// check that the response authenticates the request, whose response header byte is expected
+    pub(crate) fn verify(&self, expected: u8) -> io::Result<()> {
+        if self.response_header != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "unexpected response header, expect {}, got {}",
+                    expected, self.response_header
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// switch account command to example.com:443, alter id 4, level 1, valid 10 minutes
+    fn switch_account() -> Vec<u8> {
+        let mut data = vec![11];
+        data.extend_from_slice(b"example.com");
+        data.extend_from_slice(&443u16.to_be_bytes());
+        data.extend_from_slice(Uuid::from_u128(1).as_bytes());
+        data.extend_from_slice(&4u16.to_be_bytes());
+        data.extend_from_slice(&[1, 10]);
+        data
+    }
+
+    #[test]
+    fn response_header_without_command() {
+        let header = ResponseHeader::decode(&[0x2a, 0, 0, 0]).unwrap();
+        assert_eq!(header.command, None);
+        header.verify(0x2a).unwrap();
+    }
+
+    #[test]
+    fn response_header_mismatch() {
+        let header = ResponseHeader::decode(&[0x2a, 0, 0, 0]).unwrap();
+        let e = header.verify(0x2b).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn response_header_switch_account() {
+        let data = switch_account();
+        let mut buf = vec![0x2a, 0, 0x01, data.len() as u8];
+        buf.extend_from_slice(&data);
+        let header = ResponseHeader::decode(&buf).unwrap();
+        assert_eq!(
+            header.command,
+            Some(ResponseCommand::SwitchAccount {
+                host: "example.com".to_string(),
+                port: 443,
+                id: Uuid::from_u128(1),
+                alter_id: 4,
+                level: 1,
+                valid_minutes: 10,
+            })
+        );
+    }
+
+    #[test]
+    fn response_header_truncated_command() {
+        let data = switch_account();
+        let mut buf = vec![0x2a, 0, 0x01, data.len() as u8];
+        buf.extend_from_slice(&data[..data.len() - 1]);
+        let e = ResponseHeader::decode(&buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn response_header_ignores_short_switch_account() {
+        // the length covers the data, which is too short for the command
+        let data = &switch_account()[..20];
+        let mut buf = vec![0x2a, 0, 0x01, data.len() as u8];
+        buf.extend_from_slice(data);
+        assert_eq!(ResponseHeader::decode(&buf).unwrap().command, None);
+    }
 }
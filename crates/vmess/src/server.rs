@@ -0,0 +1,635 @@
+//! VMess inbound
+//!
+//! Authenticates the AEAD request headers of the clients, or their legacy headers if
+//! they have alter ids, and relays their streams, datagrams and mux sub-streams
+//! to the requested targets directly.
+//! A client failing the authentication is not told so: its connection is drained,
+//! like v2ray does, before it is closed.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use aes::Aes128;
+use cfb_mode::BufDecryptor;
+use log::{debug, error, info, trace};
+use rand::Rng;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::{lookup_host, TcpListener, TcpStream, UdpSocket},
+    time::{timeout_at, Instant},
+};
+use uuid::Uuid;
+
+use crate::{
+    aead::{AEADHeader, ReplayFilter, ID},
+    crypto::fnv::fnv,
+    env::Env,
+    legacy::{self, LegacyHeader},
+    mux,
+    protocol::{RequestCommand, RequestHeader, RequestOption, VERSION},
+    stream::{HeaderMode, Session, VMESSStream, MAX_DATAGRAM_SIZE},
+    Address,
+};
+
+/// time given to a client to send its request header
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(4);
+
+/// bounds of the number of bytes drained from a client failing the authentication,
+/// the number is fixed for a set of clients, plus a random part for each connection
+const DRAIN_MIN_SIZE: usize = 16 + 38;
+const DRAIN_MAX_SIZE: usize = 3266;
+const DRAIN_RANDOM_SIZE: usize = 64;
+
+pub struct VmessServer {
+    pub listener: TcpListener,
+    /// ids of the clients allowed to connect
+    ids: Arc<[ID]>,
+    /// ids and alter ids accepted in legacy headers with the index of their client,
+    /// empty without alter ids
+    legacy_ids: Arc<[(usize, ID)]>,
+    /// auth ids seen recently, shared by all connections
+    replay_filter: Arc<Mutex<ReplayFilter>>,
+    /// clock checking the auth ids and rng padding the chunks
+    env: Env,
+    /// bytes drained from a client failing the authentication, without the random part
+    drain_size: usize,
+}
+
+impl VmessServer {
+    pub async fn new(addr: &str, clients: &[Uuid]) -> io::Result<Self> {
+        if clients.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "VMess server requires at least one client",
+            ));
+        }
+        info!("Starting vmess server on {}", addr);
+        let ids: Arc<[ID]> = clients.iter().map(|&uuid| ID::new(uuid)).collect();
+        let keys: Vec<u8> = ids.iter().flat_map(|id| id.cmd_key).collect();
+        let drain_size = DRAIN_MIN_SIZE + fnv(&keys) as usize % (DRAIN_MAX_SIZE - DRAIN_MIN_SIZE);
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+            ids,
+            legacy_ids: Arc::new([]),
+            replay_filter: Arc::new(Mutex::new(ReplayFilter::new())),
+            env: Env::default(),
+            drain_size,
+        })
+    }
+
+    /// accept the legacy header from clients with alter_id alter ids,
+    /// only the AEAD header is accepted if it is 0
+    pub fn with_alter_id(mut self, alter_id: u16) -> Self {
+        self.legacy_ids = if alter_id == 0 {
+            Arc::new([])
+        } else {
+            self.ids
+                .iter()
+                .enumerate()
+                .flat_map(|(index, id)| {
+                    let alter_ids = legacy::alter_ids(id, alter_id);
+                    std::iter::once(*id)
+                        .chain(alter_ids)
+                        .map(move |auth_id| (index, auth_id))
+                })
+                .collect()
+        };
+        self
+    }
+
+    /// replace the system clock and the rng
+    pub fn with_env(mut self, env: Env) -> Self {
+        self.env = env;
+        self
+    }
+
+    pub async fn serve(&mut self) -> io::Result<()> {
+        info!("Serving vmess server");
+        loop {
+            let (stream, peer_addr) = self.listener.accept().await?;
+            info!("Accepted connection from {}", peer_addr);
+            let ids = self.ids.clone();
+            let legacy_ids = self.legacy_ids.clone();
+            let replay_filter = self.replay_filter.clone();
+            let env = self.env.clone();
+            let drain_size = self.drain_size;
+            tokio::spawn(async move {
+                let client = VmessServer::handle_client(
+                    stream,
+                    peer_addr,
+                    &ids,
+                    &legacy_ids,
+                    &replay_filter,
+                    &env,
+                    drain_size,
+                );
+                if let Err(e) = client.await {
+                    error!("Error handling vmess client {}: {}", peer_addr, e);
+                }
+            });
+        }
+    }
+
+    async fn handle_client(
+        mut stream: TcpStream,
+        peer: SocketAddr,
+        ids: &[ID],
+        legacy_ids: &[(usize, ID)],
+        replay_filter: &Mutex<ReplayFilter>,
+        env: &Env,
+        drain_size: usize,
+    ) -> io::Result<()> {
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+        let request = timeout_at(
+            deadline,
+            Self::read_request(&mut stream, ids, legacy_ids, replay_filter, env),
+        )
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request header timed out"))?;
+        let (request, session) = match request {
+            Ok(request) => request,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                // closing right away would tell a prober where the header check failed
+                let size = drain_size + env.rng().gen_range(0..DRAIN_RANDOM_SIZE);
+                match timeout_at(deadline, drain(&mut stream, size)).await {
+                    Ok(Ok(n)) => debug!("Drained {} bytes from rejected client {}", n, peer),
+                    _ => debug!("Stopped draining rejected client {}", peer),
+                }
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        trace!("Request header: {:?}", request);
+        if request.version != VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unsupported version: {}", request.version),
+            ));
+        }
+        if !RequestOption::ChunkStream.is_set(request.option) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "requests without chunk stream are not supported",
+            ));
+        }
+        RequestOption::check(request.option, request.security)?;
+        info!(
+            "{} requests {:?} to {}, security {:?}",
+            peer, request.command, request.address, request.security
+        );
+
+        match request.command {
+            RequestCommand::Tcp => {
+                let mut target = connect_tcp(&request.address).await?;
+                let mut stream = VMESSStream::accept(stream, &request, session, env.rng());
+                let (up, down) = tokio::io::copy_bidirectional(&mut stream, &mut target).await?;
+                debug!("{} relayed {} bytes up, {} bytes down", peer, up, down);
+                Ok(())
+            }
+            RequestCommand::Udp => {
+                let target = resolve(&request.address).await?;
+                let bind_addr: SocketAddr = if target.is_ipv4() {
+                    ([0u8; 4], 0).into()
+                } else {
+                    ([0u8; 16], 0).into()
+                };
+                let socket = UdpSocket::bind(bind_addr).await?;
+                socket.connect(target).await?;
+                let stream = VMESSStream::accept(stream, &request, session, env.rng());
+                relay_udp(stream, socket).await
+            }
+            RequestCommand::Mux => {
+                mux::serve(VMESSStream::accept(stream, &request, session, env.rng())).await
+            }
+        }
+    }
+
+    /// read and open the request header, find the client it comes from
+    pub(crate) async fn read_request(
+        stream: &mut TcpStream,
+        ids: &[ID],
+        legacy_ids: &[(usize, ID)],
+        replay_filter: &Mutex<ReplayFilter>,
+        env: &Env,
+    ) -> io::Result<(RequestHeader, Session)> {
+        // shorter than any legacy header too
+        let mut prefix = [0u8; AEADHeader::PREFIX_SIZE];
+        stream.read_exact(&mut prefix).await?;
+        let auth_id: [u8; 16] = prefix[..16].try_into().expect("length is 16");
+        let nonce: [u8; 8] = prefix[34..].try_into().expect("length is 8");
+
+        let now = env.now();
+        let index = match AEADHeader::match_auth_id(ids, &auth_id, now) {
+            Ok((index, _)) => index,
+            Err(e) => match LegacyHeader::open_auth(legacy_ids, &auth_id, now) {
+                Some((index, header)) => {
+                    let id = &ids[index];
+                    trace!("Matched legacy client {}", id.id);
+                    let mut decryptor = header.command_decryptor(id);
+                    let mut command = prefix[16..].to_vec();
+                    decryptor.decrypt(&mut command);
+                    return read_legacy_command(stream, decryptor, command, replay_filter, now)
+                        .await;
+                }
+                None => return Err(e),
+            },
+        };
+        if !replay_filter.lock().unwrap().check(&auth_id, now as u64) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "replayed auth id",
+            ));
+        }
+        let id = &ids[index];
+        trace!("Matched client {}", id.id);
+
+        let length = AEADHeader::open_length(id, &auth_id, &nonce, &prefix[16..34])?;
+        let mut payload = vec![0u8; length as usize + AEADHeader::TAG_SIZE];
+        stream.read_exact(&mut payload).await?;
+        let header = AEADHeader::open_payload(id, &auth_id, &nonce, &payload)?;
+
+        let (request, body_iv, body_key, response_header) = RequestHeader::decode(&header)?;
+        Ok((
+            request,
+            Session::from_request(HeaderMode::Aead, body_key, body_iv, response_header),
+        ))
+    }
+}
+
+/// read the rest of a legacy command section, its first bytes are already in command,
+/// its length is known once the command and the address type are decrypted
+async fn read_legacy_command(
+    stream: &mut TcpStream,
+    mut decryptor: BufDecryptor<Aes128>,
+    mut command: Vec<u8>,
+    replay_filter: &Mutex<ReplayFilter>,
+    now: i64,
+) -> io::Result<(RequestHeader, Session)> {
+    // version, iv, key, response header, option, padding and security, reserved, command
+    read_decrypted(stream, &mut decryptor, &mut command, 38).await?;
+    let mut len = 38;
+    // mux requests carry no address
+    if command[37] != RequestCommand::Mux as u8 {
+        // port and address type
+        len += 3;
+        read_decrypted(stream, &mut decryptor, &mut command, len).await?;
+        len += match command[40] {
+            0x01 => 4,
+            0x02 => {
+                read_decrypted(stream, &mut decryptor, &mut command, len + 1).await?;
+                1 + command[len] as usize
+            }
+            0x03 => 16,
+            address_type => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown address type: {}", address_type),
+                ))
+            }
+        };
+    }
+    // padding and checksum
+    len += (command[35] >> 4) as usize + 4;
+    read_decrypted(stream, &mut decryptor, &mut command, len).await?;
+
+    let (request, body_iv, body_key, response_header) = RequestHeader::decode(&command)?;
+    // the authentication is the same for all the requests of a client in one second,
+    // the body iv tells the replays
+    if !replay_filter.lock().unwrap().check(&body_iv, now as u64) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "replayed legacy request",
+        ));
+    }
+    Ok((
+        request,
+        Session::from_request(HeaderMode::Legacy, body_key, body_iv, response_header),
+    ))
+}
+
+/// read and decrypt the bytes of command up to len
+async fn read_decrypted(
+    stream: &mut TcpStream,
+    decryptor: &mut BufDecryptor<Aes128>,
+    command: &mut Vec<u8>,
+    len: usize,
+) -> io::Result<()> {
+    let start = command.len();
+    if len > start {
+        command.resize(len, 0);
+        stream.read_exact(&mut command[start..]).await?;
+        decryptor.decrypt(&mut command[start..]);
+    }
+    Ok(())
+}
+
+/// read and discard up to size bytes, return how many were read
+async fn drain(stream: &mut TcpStream, size: usize) -> io::Result<u64> {
+    tokio::io::copy(&mut stream.take(size as u64), &mut tokio::io::sink()).await
+}
+
+pub(crate) async fn connect_tcp(address: &Address) -> io::Result<TcpStream> {
+    match address {
+        Address::SocketAddr(addr) => TcpStream::connect(addr).await,
+        Address::DomainName(domain, port) => TcpStream::connect((domain.as_str(), *port)).await,
+    }
+}
+
+pub(crate) async fn resolve(address: &Address) -> io::Result<SocketAddr> {
+    match address {
+        Address::SocketAddr(addr) => Ok(*addr),
+        Address::DomainName(domain, port) => lookup_host((domain.as_str(), *port))
+            .await?
+            .next()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Failed to resolve {}", domain),
+                )
+            }),
+    }
+}
+
+/// relay the datagrams between the client and the target until the client ends the stream
+async fn relay_udp(stream: VMESSStream, socket: UdpSocket) -> io::Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    tokio::select! {
+        result = udp_uplink(&mut reader, &socket) => result?,
+        result = udp_downlink(&socket, &mut writer) => result?,
+    }
+    writer.shutdown().await
+}
+
+async fn udp_uplink(reader: &mut ReadHalf<VMESSStream>, socket: &UdpSocket) -> io::Result<()> {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        // each read returns one datagram
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        socket.send(&buf[..n]).await?;
+    }
+}
+
+async fn udp_downlink(socket: &UdpSocket, writer: &mut WriteHalf<VMESSStream>) -> io::Result<()> {
+    // longer datagrams are truncated
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let n = socket.recv(&mut buf).await?;
+        writer.write_all(&buf[..n]).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::{
+        mux::{MuxClient, MuxConfig},
+        udp::VMESSUdpSession,
+        Encryption, VMESSOptions, VmessUser,
+    };
+
+    const UUID: Uuid = Uuid::from_u128(0x7365_7276_6572);
+    const ALTER_ID: u16 = 4;
+
+    /// start a server of UUID accepting the legacy header with alter_id, return its address
+    async fn start_server(alter_id: u16) -> SocketAddr {
+        let mut server = VmessServer::new("127.0.0.1:0", &[Uuid::from_u128(2), UUID])
+            .await
+            .unwrap()
+            .with_alter_id(alter_id);
+        let addr = server.listener.local_addr().unwrap();
+        tokio::spawn(async move { server.serve().await });
+        addr
+    }
+
+    /// start a tcp target echoing every connection
+    async fn echo_target() -> Address {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.into_split();
+                    tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+                    writer.shutdown().await.unwrap();
+                });
+            }
+        });
+        Address::SocketAddr(addr)
+    }
+
+    fn users(alter_id: u16) -> Vec<VmessUser> {
+        let mut users = Vec::new();
+        for (security, options) in [
+            (Encryption::NONE, vec![]),
+            (
+                Encryption::AES128GCM,
+                vec![VMESSOptions::M, VMESSOptions::P],
+            ),
+            (Encryption::CHACHA20POLY1305, vec![VMESSOptions::M]),
+            (Encryption::AES128GCM, vec![VMESSOptions::A]),
+        ] {
+            let mut user = VmessUser::new(UUID)
+                .with_alter_id(alter_id)
+                .with_security(security);
+            user.options = options;
+            users.push(user);
+        }
+        users
+    }
+
+    /// relay data to the echo target through the server, then half-close
+    async fn echo_tcp(server: SocketAddr, user: &VmessUser) {
+        let target = echo_target().await;
+        let mut stream = VMESSStream::connect(server, target, user).await.unwrap();
+        let data: Vec<u8> = (0..40000).map(|i| (i % 251) as u8).collect();
+        stream.write_all(&data).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        timeout(Duration::from_secs(5), stream.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(received == data, "{:?}", user);
+    }
+
+    #[tokio::test]
+    async fn tcp_aead() {
+        let server = start_server(0).await;
+        for user in users(0) {
+            echo_tcp(server, &user).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn tcp_legacy() {
+        let server = start_server(ALTER_ID).await;
+        for user in users(ALTER_ID) {
+            echo_tcp(server, &user).await;
+        }
+        // the AEAD header is still accepted
+        echo_tcp(server, &VmessUser::new(UUID)).await;
+    }
+
+    #[tokio::test]
+    async fn udp() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = Address::SocketAddr(target.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            loop {
+                let (n, peer) = target.recv_from(&mut buf).await.unwrap();
+                target.send_to(&buf[..n], peer).await.unwrap();
+            }
+        });
+
+        let server = start_server(ALTER_ID).await;
+        for user in [
+            VmessUser::new(UUID),
+            VmessUser::new(UUID).with_alter_id(ALTER_ID),
+        ] {
+            let mut session = VMESSUdpSession::connect(server, target_addr.clone(), &user)
+                .await
+                .unwrap();
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            for datagram in [&b"first"[..], &[7u8; 3000], b"third"] {
+                session.send(datagram).await.unwrap();
+                let n = timeout(Duration::from_secs(5), session.recv(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(&buf[..n], datagram);
+            }
+            session.close().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn mux() {
+        let server = start_server(ALTER_ID).await;
+        let target = echo_target().await;
+        // mux requests carry no address, the legacy header is shorter
+        for user in [
+            VmessUser::new(UUID),
+            VmessUser::new(UUID).with_alter_id(ALTER_ID),
+        ] {
+            let client = MuxClient::new(server.to_string(), user, MuxConfig::default());
+            let mut first = client.connect(target.clone()).await.unwrap();
+            let mut second = client.connect(target.clone()).await.unwrap();
+            for (stream, data) in [(&mut first, b"first"), (&mut second, b"other")] {
+                stream.write_all(data).await.unwrap();
+                let mut buf = [0u8; 5];
+                timeout(Duration::from_secs(5), stream.read_exact(&mut buf))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(&buf, data);
+            }
+        }
+    }
+
+    /// the bytes sent by user for one request, up to the end chunk
+    async fn record_request(user: &VmessUser) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = Address::SocketAddr(([127, 0, 0, 1], 80).into());
+        let mut client = VMESSStream::connect(listener.local_addr().unwrap(), target, user)
+            .await
+            .unwrap();
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        accepted.read_to_end(&mut request).await.unwrap();
+        request
+    }
+
+    /// send request to read_request
+    async fn replay(
+        request: &[u8],
+        server: &VmessServer,
+        env: &Env,
+    ) -> io::Result<(RequestHeader, Session)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        client.write_all(request).await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+        VmessServer::read_request(
+            &mut accepted,
+            &server.ids,
+            &server.legacy_ids,
+            &server.replay_filter,
+            env,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn replayed_request_is_rejected() {
+        let server = VmessServer::new("127.0.0.1:0", &[UUID])
+            .await
+            .unwrap()
+            .with_alter_id(ALTER_ID);
+        for user in [
+            VmessUser::new(UUID),
+            VmessUser::new(UUID).with_alter_id(ALTER_ID),
+        ] {
+            let request = record_request(&user).await;
+            let (header, _) = replay(&request, &server, &user.env).await.unwrap();
+            assert_eq!(header.command, RequestCommand::Tcp);
+            let e = replay(&request, &server, &user.env).await.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:?}", user);
+
+            // another request of the same user is accepted
+            let request = record_request(&user).await;
+            replay(&request, &server, &user.env).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_client_is_rejected() {
+        let server = VmessServer::new("127.0.0.1:0", &[UUID])
+            .await
+            .unwrap()
+            .with_alter_id(ALTER_ID);
+        let user = VmessUser::new(Uuid::from_u128(3));
+        let request = record_request(&user).await;
+        let e = replay(&request, &server, &user.env).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        let user = VmessUser::new(Uuid::from_u128(3)).with_alter_id(ALTER_ID);
+        let request = record_request(&user).await;
+        let e = replay(&request, &server, &user.env).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn bad_auth_id_is_drained() {
+        let server = start_server(0).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        stream.write_all(&[0x55; 100]).await.unwrap();
+        // the connection is kept open while the server drains it
+        let mut buf = [0u8; 16];
+        assert!(timeout(Duration::from_millis(500), stream.read(&mut buf))
+            .await
+            .is_err());
+
+        // closed once more than drained has been sent, before the handshake timeout
+        let junk = vec![0x55; DRAIN_MAX_SIZE + DRAIN_RANDOM_SIZE];
+        let _ = stream.write_all(&junk).await;
+        match timeout(Duration::from_secs(1), stream.read(&mut buf)).await {
+            Ok(Ok(n)) => assert_eq!(n, 0),
+            Ok(Err(e)) => assert_eq!(e.kind(), io::ErrorKind::ConnectionReset),
+            Err(_) => panic!("the connection is still open"),
+        }
+    }
+}
@@ -4,49 +4,599 @@ use std::result::Result;
 use std::task;
 use std::{io, net::SocketAddr, task::Poll};
 
-use log::info;
-use md5::Digest;
-use rand::Rng;
-use sha2::Sha256;
+use aes::Aes128;
+use aes_gcm::{aead::Aead, Aes128Gcm, KeyInit, Nonce};
+use cfb_mode::BufDecryptor;
+use chacha20poly1305::ChaCha20Poly1305;
+use futures::ready;
+use log::{info, trace, warn};
+use md5::Md5;
+use rand::{Rng, RngCore};
+use sha2::{Digest, Sha256};
 use tokio::io::{AsyncRead, ReadBuf};
 use tokio::{
     io::AsyncWrite,
     net::{TcpStream, ToSocketAddrs},
 };
-use uuid::uuid;
 
-use crate::aead::{AEADHeader, ID};
-use crate::crypto::fnv::fnv;
-use crate::protocol::{RequestCommand, RequestHeader, RequestOption, RequestSecurity, VERSION};
+use crate::aead::{AEADHeader, AEADResponseHeader};
+use crate::crypto::{chacha20poly1305_key, kdf, shake::ShakeMask, KDF_SALT_AUTH_LEN};
+use crate::env::ConnectionRng;
+use crate::legacy::{self, LegacyHeader};
+use crate::protocol::{
+    RequestCommand, RequestHeader, RequestOption, RequestSecurity, ResponseHeader, VERSION,
+};
+use crate::{Address, Encryption, VmessUser};
+
+/// max size of the data carried by one chunk
+const MAX_CHUNK_SIZE: usize = 1 << 13;
+
+/// max size of a datagram, a chunk also carries the tag and up to 63 bytes of padding
+pub const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize - 16 - 64;
+
+/// size of each read from the underlying stream
+const READ_SIZE: usize = 1 << 14;
+
+/// Format of the request and response headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum HeaderMode {
+    #[default]
+    Aead,
+    /// MD5 authentication and AES-128-CFB encryption, used by servers requiring alter ids
+    Legacy,
+}
 
+/// Keys of a VMess session, generated once per connection
 #[derive(Debug, Default)]
-#[allow(dead_code)]
 pub struct Session {
-    request_body_key: [u8; 16],
-    request_body_iv: [u8; 16],
+    pub(crate) request_body_key: [u8; 16],
+    pub(crate) request_body_iv: [u8; 16],
     response_body_key: [u8; 16],
     response_body_iv: [u8; 16],
     response_header: u8,
+    mode: HeaderMode,
+}
+
+impl Session {
+    /// create a session with request keys drawn from rng
+    pub(crate) fn new(mode: HeaderMode, rng: &mut dyn RngCore) -> Self {
+        let request_body_key: [u8; 16] = rng.gen();
+        let request_body_iv: [u8; 16] = rng.gen();
+        let response_header = rng.gen();
+        Self::from_request(mode, request_body_key, request_body_iv, response_header)
+    }
+
+    /// create the session of a request, as sent by the client or received by the server
+    pub(crate) fn from_request(
+        mode: HeaderMode,
+        request_body_key: [u8; 16],
+        request_body_iv: [u8; 16],
+        response_header: u8,
+    ) -> Self {
+        // response key and iv are the first 16 bytes of the sha256 of the request ones,
+        // or the md5 of them in legacy mode
+        let (response_body_key, response_body_iv) = match mode {
+            HeaderMode::Aead => (
+                Sha256::digest(request_body_key)[..16]
+                    .try_into()
+                    .expect("length is 16"),
+                Sha256::digest(request_body_iv)[..16]
+                    .try_into()
+                    .expect("length is 16"),
+            ),
+            HeaderMode::Legacy => (
+                Md5::digest(request_body_key).into(),
+                Md5::digest(request_body_iv).into(),
+            ),
+        };
+
+        Session {
+            request_body_key,
+            request_body_iv,
+            response_body_key,
+            response_body_iv,
+            response_header,
+            mode,
+        }
+    }
+}
+
+/// AEAD cipher of the chunk data
+enum ChunkCipher {
+    None,
+    Aes128Gcm(Box<Aes128Gcm>),
+    ChaCha20Poly1305(Box<ChaCha20Poly1305>),
+}
+
+/// Seals and opens the data of chunks in one direction.
+///
+/// The nonce of each chunk is a big endian counter followed by bytes 2..12 of the body iv.
+pub(crate) struct ChunkAuth {
+    cipher: ChunkCipher,
+    nonce: [u8; 12],
+    count: u16,
+}
+
+impl ChunkAuth {
+    pub(crate) fn new(security: RequestSecurity, key: &[u8; 16], iv: &[u8; 16]) -> Self {
+        let cipher = match security {
+            RequestSecurity::AES128GCM => {
+                ChunkCipher::Aes128Gcm(Box::new(Aes128Gcm::new(key.into())))
+            }
+            RequestSecurity::CHACHA20POLY1305 => ChunkCipher::ChaCha20Poly1305(Box::new(
+                ChaCha20Poly1305::new((&chacha20poly1305_key(key)).into()),
+            )),
+            _ => ChunkCipher::None,
+        };
+        Self {
+            cipher,
+            nonce: iv[..12].try_into().expect("length is 12"),
+            count: 0,
+        }
+    }
+
+    /// size of the tag appended to the data
+    pub(crate) fn overhead(&self) -> usize {
+        match self.cipher {
+            ChunkCipher::None => 0,
+            ChunkCipher::Aes128Gcm(_) | ChunkCipher::ChaCha20Poly1305(_) => 16,
+        }
+    }
+
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = self.nonce;
+        nonce[..2].copy_from_slice(&self.count.to_be_bytes());
+        self.count = self.count.wrapping_add(1);
+        nonce
+    }
+
+    /// append the sealed data to buf
+    pub(crate) fn seal(&mut self, data: &[u8], buf: &mut Vec<u8>) {
+        let nonce = self.next_nonce();
+        match &self.cipher {
+            ChunkCipher::None => buf.extend_from_slice(data),
+            ChunkCipher::Aes128Gcm(cipher) => buf.extend_from_slice(
+                &cipher
+                    .encrypt(Nonce::from_slice(&nonce), data)
+                    .expect("encryption failure!"),
+            ),
+            ChunkCipher::ChaCha20Poly1305(cipher) => buf.extend_from_slice(
+                &cipher
+                    .encrypt(Nonce::from_slice(&nonce), data)
+                    .expect("encryption failure!"),
+            ),
+        }
+    }
+
+    pub(crate) fn open(&mut self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        match &self.cipher {
+            ChunkCipher::None => Ok(data.to_vec()),
+            ChunkCipher::Aes128Gcm(cipher) => cipher.decrypt(Nonce::from_slice(&nonce), data),
+            ChunkCipher::ChaCha20Poly1305(cipher) => {
+                cipher.decrypt(Nonce::from_slice(&nonce), data)
+            }
+        }
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "failed to open chunk"))
+    }
+}
+
+/// Framing of the chunks in one direction, shared by `ChunkWriter` and `ChunkReader`
+pub(crate) struct ChunkCodec {
+    auth: ChunkAuth,
+    /// mask of the chunk length, enabled by the chunk masking option
+    mask: Option<ShakeMask>,
+    /// whether random padding is appended to each chunk, its length comes from the mask
+    padding: bool,
+    /// seals the chunk length, enabled by the authenticated length option,
+    /// it replaces the mask of the length but the padding still comes from the mask
+    length_auth: Option<ChunkAuth>,
+}
+
+impl ChunkCodec {
+    pub(crate) fn new(
+        security: RequestSecurity,
+        command: RequestCommand,
+        option: u8,
+        key: &[u8; 16],
+        iv: &[u8; 16],
+    ) -> Self {
+        let mask = if RequestOption::ChunkMasking.is_set(option) {
+            Some(ShakeMask::new(iv))
+        } else {
+            None
+        };
+        // unencrypted tcp chunks are never padded, options are checked so the mask is set
+        let padding = RequestOption::GlobalPadding.is_set(option)
+            && (security != RequestSecurity::None || command == RequestCommand::Udp);
+        // options are checked so the chunks are encrypted
+        let length_auth = if RequestOption::AuthenticatedLength.is_set(option) {
+            let length_key = kdf(key, &[KDF_SALT_AUTH_LEN]);
+            let length_key = length_key[..16].try_into().expect("length is 16");
+            Some(ChunkAuth::new(security, &length_key, iv))
+        } else {
+            None
+        };
+        Self {
+            auth: ChunkAuth::new(security, key, iv),
+            mask,
+            padding,
+            length_auth,
+        }
+    }
+
+    /// length of the padding of the next chunk, must be called before the size is encoded
+    fn next_padding(&mut self) -> usize {
+        match &mut self.mask {
+            Some(mask) if self.padding => (mask.next() % 64) as usize,
+            _ => 0,
+        }
+    }
+
+    /// size of the encoded length
+    fn size_bytes(&self) -> usize {
+        match &self.length_auth {
+            Some(length_auth) => 2 + length_auth.overhead(),
+            None => 2,
+        }
+    }
+
+    fn encode_size(&mut self, size: usize, buf: &mut Vec<u8>) {
+        if let Some(length_auth) = &mut self.length_auth {
+            // the tag of the data is not counted in the authenticated length
+            let size = (size - self.auth.overhead()) as u16;
+            length_auth.seal(&size.to_be_bytes(), buf);
+            return;
+        }
+        let mut size = size as u16;
+        if let Some(mask) = &mut self.mask {
+            size ^= mask.next();
+        }
+        buf.extend_from_slice(&size.to_be_bytes());
+    }
+
+    fn decode_size(&mut self, data: &[u8]) -> io::Result<usize> {
+        if let Some(length_auth) = &mut self.length_auth {
+            let size = length_auth.open(data)?;
+            let size = u16::from_be_bytes([size[0], size[1]]) as usize;
+            return Ok(size + self.auth.overhead());
+        }
+        let mut size = u16::from_be_bytes([data[0], data[1]]);
+        if let Some(mask) = &mut self.mask {
+            size ^= mask.next();
+        }
+        Ok(size as usize)
+    }
+}
+
+/// Encodes data into chunks: the length, masked or sealed if required, followed by the sealed data
+pub(crate) struct ChunkWriter {
+    codec: ChunkCodec,
+    /// source of the padding
+    rng: ConnectionRng,
+}
+
+impl ChunkWriter {
+    pub(crate) fn new(codec: ChunkCodec, rng: ConnectionRng) -> Self {
+        Self { codec, rng }
+    }
+
+    /// append one chunk of data to buf, data should not be longer than `MAX_CHUNK_SIZE`
+    pub(crate) fn write_chunk(&mut self, data: &[u8], buf: &mut Vec<u8>) {
+        let padding = self.codec.next_padding();
+        let size = data.len() + self.codec.auth.overhead() + padding;
+        self.codec.encode_size(size, buf);
+        self.codec.auth.seal(data, buf);
+
+        if padding > 0 {
+            let start = buf.len();
+            buf.resize(start + padding, 0);
+            self.rng.fill(&mut buf[start..]);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum ChunkReadState {
+    /// waiting for the length
+    #[default]
+    Length,
+    /// waiting for the data of given length, which ends with the padding
+    Data { size: usize, padding: usize },
+}
+
+/// Decodes chunks written by `ChunkWriter`
+pub(crate) struct ChunkReader {
+    codec: ChunkCodec,
+    state: ChunkReadState,
+}
+
+impl ChunkReader {
+    pub(crate) fn new(codec: ChunkCodec) -> Self {
+        Self {
+            codec,
+            state: ChunkReadState::Length,
+        }
+    }
+
+    /// decode one chunk from the front of src and remove it from src,
+    /// return None if src does not hold a complete chunk yet
+    pub(crate) fn read_chunk(&mut self, src: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            match self.state {
+                ChunkReadState::Length => {
+                    let size_bytes = self.codec.size_bytes();
+                    if src.len() < size_bytes {
+                        return Ok(None);
+                    }
+                    let padding = self.codec.next_padding();
+                    let size = self.codec.decode_size(&src[..size_bytes])?;
+                    if size < padding + self.codec.auth.overhead() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("invalid chunk size {}, padding {}", size, padding),
+                        ));
+                    }
+                    src.drain(..size_bytes);
+                    self.state = ChunkReadState::Data { size, padding };
+                }
+                ChunkReadState::Data { size, padding } => {
+                    if src.len() < size {
+                        return Ok(None);
+                    }
+                    let data = self.codec.auth.open(&src[..size - padding])?;
+                    src.drain(..size);
+                    self.state = ChunkReadState::Length;
+                    return Ok(Some(data));
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReadState {
+    /// waiting for the encrypted length of the response header
+    HeaderLength,
+    /// waiting for the encrypted response header of given length
+    Header(usize),
+    /// waiting for the fixed part of the legacy response header
+    LegacyHeader,
+    /// waiting for the command of the legacy response header, following the fixed part
+    LegacyCommand([u8; 4]),
+    /// reading chunks
+    Body,
+    /// the end of stream chunk has been received
+    Eof,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WriteState {
+    /// ready to encode new data
+    Idle,
+    /// writing the encoded data, `consumed` bytes of the caller's data are in the buffer
+    Writing { consumed: usize },
+    /// writing the end of stream chunk, then shutting down the stream
+    ShuttingDown,
+    /// no more data can be written
+    Shutdown,
 }
 
 pub struct VMESSStream {
     pub stream: TcpStream,
     pub session: Session,
+    /// destination requested from the server
+    pub target: Address,
+    /// in udp mode each write is sent as one chunk and each read returns one chunk
+    command: RequestCommand,
+    /// security of the body, `AUTO` is already resolved
+    security: Encryption,
+    /// sealed request header, sent along with the first chunk
+    header: Option<Vec<u8>>,
+    /// whether the request header has been written to the stream
+    header_sent: bool,
+    write_state: WriteState,
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    chunk_writer: ChunkWriter,
+    read_state: ReadState,
+    /// decryptor of the response header in legacy mode
+    response_decryptor: Option<Box<BufDecryptor<Aes128>>>,
+    /// raw data read from the stream
+    read_buf: Vec<u8>,
+    /// decoded data not yet returned to the caller
+    plain: Vec<u8>,
+    plain_pos: usize,
+    chunk_reader: ChunkReader,
 }
 
 impl VMESSStream {
-    pub async fn connect<A>(addr: A) -> io::Result<VMESSStream>
+    /// connect to the VMess server at addr as user, the server will relay the stream to target
+    pub async fn connect<A>(addr: A, target: Address, user: &VmessUser) -> io::Result<VMESSStream>
     where
         A: ToSocketAddrs + Display,
     {
-        info!("Connecting to {}", addr);
+        Self::connect_command(addr, RequestCommand::Tcp, target, user).await
+    }
+
+    /// connect to the VMess server at addr with the given request command
+    pub(crate) async fn connect_command<A>(
+        addr: A,
+        command: RequestCommand,
+        target: Address,
+        user: &VmessUser,
+    ) -> io::Result<VMESSStream>
+    where
+        A: ToSocketAddrs + Display,
+    {
+        let option = user.option();
+        let encryption = user.security().clone();
+        let security = RequestSecurity::try_from(&encryption)?;
+        RequestOption::check(option, security)?;
+        if let Address::DomainName(domain, _) = &target {
+            if domain.len() > u8::MAX as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Domain name too long: {}", domain),
+                ));
+            }
+        }
+        info!("Connecting to {}, target: {}", addr, target);
         let stream = TcpStream::connect(addr).await?;
+
+        let mode = if user.alter_id() > 0 {
+            HeaderMode::Legacy
+        } else {
+            HeaderMode::Aead
+        };
+        let mut rng = user.env.rng();
+        let session = Session::new(mode, &mut rng);
+        let request = RequestHeader {
+            version: VERSION,
+            command,
+            option,
+            security,
+            address: target.clone(),
+        };
+        trace!("Request header: {:?}", request);
+
+        let mut padding = [0u8; 15];
+        rng.fill(&mut padding);
+        let padding_len = rng.gen_range(0..16);
+        let header_buffer = request.encode(
+            &session.request_body_iv,
+            &session.request_body_key,
+            session.response_header,
+            &padding[..padding_len],
+        );
+
+        let id = user.id();
+        let (header, read_state, response_decryptor) = match mode {
+            HeaderMode::Aead => (
+                AEADHeader::new(user.env.now(), &mut rng).seal(id, &header_buffer),
+                ReadState::HeaderLength,
+                None,
+            ),
+            HeaderMode::Legacy => {
+                // authenticate with a random alter id
+                let alter_ids = user.alter_ids();
+                let auth_id = alter_ids[rng.gen_range(0..alter_ids.len())];
+                (
+                    LegacyHeader::new(user.env.now(), &mut rng).seal(&id, &auth_id, &header_buffer),
+                    ReadState::LegacyHeader,
+                    Some(Box::new(legacy::response_decryptor(
+                        &session.response_body_key,
+                        &session.response_body_iv,
+                    ))),
+                )
+            }
+        };
+
+        let chunk_writer = ChunkWriter::new(
+            ChunkCodec::new(
+                security,
+                command,
+                option,
+                &session.request_body_key,
+                &session.request_body_iv,
+            ),
+            rng,
+        );
+        let chunk_reader = ChunkReader::new(ChunkCodec::new(
+            security,
+            command,
+            option,
+            &session.response_body_key,
+            &session.response_body_iv,
+        ));
+
         Ok(VMESSStream {
             stream,
-            session: Session::default(),
+            session,
+            target,
+            command,
+            security: encryption,
+            header: Some(header),
+            header_sent: false,
+            write_state: WriteState::Idle,
+            write_buf: Vec::with_capacity(MAX_CHUNK_SIZE + 2),
+            write_pos: 0,
+            chunk_writer,
+            read_state,
+            response_decryptor,
+            read_buf: Vec::with_capacity(READ_SIZE),
+            plain: Vec::new(),
+            plain_pos: 0,
+            chunk_reader,
         })
     }
 
+    /// serve an accepted request on the server side, stream is right after the request header,
+    /// the response header is sent along with the first chunk, rng pads the chunks
+    pub(crate) fn accept(
+        stream: TcpStream,
+        request: &RequestHeader,
+        session: Session,
+        rng: ConnectionRng,
+    ) -> VMESSStream {
+        let response = ResponseHeader {
+            response_header: session.response_header,
+            option: 0,
+            command: None,
+        };
+        let header = match session.mode {
+            HeaderMode::Aead => AEADResponseHeader::seal(
+                &session.response_body_key,
+                &session.response_body_iv,
+                &response.encode(),
+            ),
+            HeaderMode::Legacy => legacy::seal_response(
+                &session.response_body_key,
+                &session.response_body_iv,
+                &response.encode(),
+            ),
+        };
+
+        // the server writes with the response keys and reads with the request keys
+        let chunk_writer = ChunkWriter::new(
+            ChunkCodec::new(
+                request.security,
+                request.command,
+                request.option,
+                &session.response_body_key,
+                &session.response_body_iv,
+            ),
+            rng,
+        );
+        let chunk_reader = ChunkReader::new(ChunkCodec::new(
+            request.security,
+            request.command,
+            request.option,
+            &session.request_body_key,
+            &session.request_body_iv,
+        ));
+
+        VMESSStream {
+            stream,
+            session,
+            target: request.address.clone(),
+            command: request.command,
+            security: request.security.into(),
+            header: Some(header),
+            header_sent: false,
+            write_state: WriteState::Idle,
+            write_buf: Vec::with_capacity(MAX_CHUNK_SIZE + 2),
+            write_pos: 0,
+            chunk_writer,
+            read_state: ReadState::Body,
+            response_decryptor: None,
+            read_buf: Vec::with_capacity(READ_SIZE),
+            plain: Vec::new(),
+            plain_pos: 0,
+            chunk_reader,
+        }
+    }
+
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         self.stream.local_addr()
     }
@@ -54,6 +604,137 @@ impl VMESSStream {
     pub fn buffer_size(&self) -> usize {
         1 << 14
     }
+
+    /// security used by the body
+    pub fn security(&self) -> &Encryption {
+        &self.security
+    }
+
+    /// write all pending data in the write buffer to the stream
+    fn poll_write_buf(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(
+                Pin::new(&mut self.stream).poll_write(cx, &self.write_buf[self.write_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "write zero byte into stream",
+                )));
+            }
+            self.write_pos += n;
+        }
+        self.write_buf.clear();
+        self.write_pos = 0;
+        if self.header.is_none() {
+            self.header_sent = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// read more data from the stream into the read buffer, return the number of bytes read
+    fn poll_read_buf(&mut self, cx: &mut task::Context<'_>) -> Poll<io::Result<usize>> {
+        let len = self.read_buf.len();
+        self.read_buf.resize(len + READ_SIZE, 0);
+        let mut buf = ReadBuf::new(&mut self.read_buf[len..]);
+        let result = Pin::new(&mut self.stream).poll_read(cx, &mut buf);
+        let n = buf.filled().len();
+        self.read_buf.truncate(len + n);
+        ready!(result)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn legacy_decrypt(&mut self, data: &mut [u8]) {
+        self.response_decryptor
+            .as_mut()
+            .expect("decryptor is set in legacy mode")
+            .decrypt(data);
+    }
+
+    /// decode the response header and check that it matches the request
+    fn check_response_header(&self, buf: &[u8]) -> io::Result<()> {
+        let header = ResponseHeader::decode(buf)?;
+        trace!("Response header: {:?}", header);
+        header.verify(self.session.response_header)?;
+        if let Some(command) = header.command {
+            warn!("Response command is not supported: {:?}", command);
+        }
+        Ok(())
+    }
+
+    /// decode as much as possible from the read buffer,
+    /// return true if there is decoded data for the caller
+    fn decode_read_buf(&mut self) -> io::Result<bool> {
+        loop {
+            match self.read_state {
+                ReadState::HeaderLength => {
+                    if self.read_buf.len() < AEADResponseHeader::LENGTH_SIZE {
+                        return Ok(false);
+                    }
+                    let length = AEADResponseHeader::open_length(
+                        &self.session.response_body_key,
+                        &self.session.response_body_iv,
+                        &self.read_buf[..AEADResponseHeader::LENGTH_SIZE],
+                    )?;
+                    self.read_buf.drain(..AEADResponseHeader::LENGTH_SIZE);
+                    self.read_state = ReadState::Header(length as usize);
+                }
+                ReadState::Header(length) => {
+                    let length = length + AEADResponseHeader::TAG_SIZE;
+                    if self.read_buf.len() < length {
+                        return Ok(false);
+                    }
+                    let header = AEADResponseHeader::open(
+                        &self.session.response_body_key,
+                        &self.session.response_body_iv,
+                        &self.read_buf[..length],
+                    )?;
+                    self.read_buf.drain(..length);
+
+                    self.check_response_header(&header)?;
+                    self.read_state = ReadState::Body;
+                }
+                ReadState::LegacyHeader => {
+                    if self.read_buf.len() < 4 {
+                        return Ok(false);
+                    }
+                    let mut header: [u8; 4] = self.read_buf[..4].try_into().expect("length is 4");
+                    self.legacy_decrypt(&mut header);
+                    self.read_buf.drain(..4);
+                    self.read_state = ReadState::LegacyCommand(header);
+                }
+                ReadState::LegacyCommand(header) => {
+                    let length = header[3] as usize;
+                    if self.read_buf.len() < length {
+                        return Ok(false);
+                    }
+                    let mut buf = header.to_vec();
+                    buf.extend(self.read_buf.drain(..length));
+                    self.legacy_decrypt(&mut buf[4..]);
+                    self.check_response_header(&buf)?;
+                    self.response_decryptor = None;
+                    self.read_state = ReadState::Body;
+                }
+                ReadState::Body => {
+                    return match self.chunk_reader.read_chunk(&mut self.read_buf)? {
+                        // an empty chunk ends the stream
+                        Some(data) if data.is_empty() => {
+                            trace!("End of stream chunk received");
+                            self.read_state = ReadState::Eof;
+                            Ok(false)
+                        }
+                        Some(data) => {
+                            self.plain = data;
+                            self.plain_pos = 0;
+                            Ok(true)
+                        }
+                        None => Ok(false),
+                    };
+                }
+                ReadState::Eof => return Ok(false),
+            }
+        }
+    }
 }
 
 impl AsyncWrite for VMESSStream {
@@ -62,96 +743,402 @@ impl AsyncWrite for VMESSStream {
         cx: &mut task::Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
-        // create a request
-        let mut request = RequestHeader {
-            version: VERSION,
-            ..Default::default()
-        };
-        request.command = RequestCommand::Tcp;
-        request.option = RequestOption::None;
-        request.security = RequestSecurity::Zero;
+        let this = self.get_mut();
+        loop {
+            match this.write_state {
+                WriteState::Idle => {
+                    if buf.is_empty() {
+                        return Poll::Ready(Ok(0));
+                    }
+                    // the header goes out only once, in front of the first chunk
+                    if let Some(header) = this.header.take() {
+                        this.write_buf.extend_from_slice(&header);
+                    }
+                    let consumed = if this.command == RequestCommand::Udp {
+                        if buf.len() > MAX_DATAGRAM_SIZE {
+                            return Poll::Ready(Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                format!("datagram too large: {}", buf.len()),
+                            )));
+                        }
+                        buf.len()
+                    } else {
+                        buf.len().min(MAX_CHUNK_SIZE)
+                    };
+                    this.chunk_writer
+                        .write_chunk(&buf[..consumed], &mut this.write_buf);
+                    this.write_state = WriteState::Writing { consumed };
+                }
+                WriteState::Writing { consumed } => {
+                    ready!(this.poll_write_buf(cx))?;
+                    this.write_state = WriteState::Idle;
+                    return Poll::Ready(Ok(consumed));
+                }
+                WriteState::ShuttingDown | WriteState::Shutdown => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "write after shutdown",
+                    )));
+                }
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // the encoded data must reach the stream before it can be flushed
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match this.write_state {
+                WriteState::Idle => {
+                    // the header is still required if nothing has been written
+                    if let Some(header) = this.header.take() {
+                        this.write_buf.extend_from_slice(&header);
+                    }
+                    // an empty chunk tells the server that the request is complete
+                    this.chunk_writer.write_chunk(&[], &mut this.write_buf);
+                    this.write_state = WriteState::ShuttingDown;
+                }
+                WriteState::Writing { .. } => {
+                    // the pending chunk is already encoded, finish writing it first
+                    ready!(this.poll_write_buf(cx))?;
+                    this.write_state = WriteState::Idle;
+                }
+                WriteState::ShuttingDown => {
+                    ready!(this.poll_write_buf(cx))?;
+                    ready!(Pin::new(&mut this.stream).poll_shutdown(cx))?;
+                    this.write_state = WriteState::Shutdown;
+                }
+                WriteState::Shutdown => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl AsyncRead for VMESSStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> task::Poll<Result<(), io::Error>> {
+        let this = self.get_mut();
+        // the server will not respond before it receives the request header
+        if !this.header_sent {
+            if let Some(header) = this.header.take() {
+                this.write_buf.extend_from_slice(&header);
+            }
+            ready!(this.poll_write_buf(cx))?;
+        }
 
-        let mut random: Vec<u8> = vec![0; 33];
-        let mut rng = rand::thread_rng();
-        rng.fill(&mut random[..]);
+        loop {
+            // return the decoded data first
+            if this.plain_pos < this.plain.len() {
+                let n = buf.remaining().min(this.plain.len() - this.plain_pos);
+                buf.put_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+                this.plain_pos += n;
+                if this.command == RequestCommand::Udp {
+                    // like a udp socket, the rest of a datagram that does not fit is discarded
+                    this.plain_pos = this.plain.len();
+                }
+                return Poll::Ready(Ok(()));
+            }
 
-        let request_body_key: [u8; 16] = random[0..16]
-            .to_vec()
-            .try_into()
-            .expect("slice with incorrect length");
-        let request_body_iv: [u8; 16] = random[16..32]
-            .to_vec()
-            .try_into()
-            .expect("slice with incorrect length");
-        // calculate crc
-        let mut sha = Sha256::new();
+            if this.decode_read_buf()? {
+                continue;
+            }
+            if this.read_state == ReadState::Eof {
+                return Poll::Ready(Ok(()));
+            }
 
-        sha.update(&random[0..16]);
+            let n = ready!(this.poll_read_buf(cx))?;
+            if n == 0 {
+                if this.read_state == ReadState::Body && this.read_buf.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "stream closed in the middle of a chunk",
+                )));
+            }
+        }
+    }
+}
 
-        // encode request header
-        let mut header_buffer = Vec::new();
-        header_buffer.push(request.version);
-        header_buffer.extend_from_slice(&request_body_iv);
-        header_buffer.extend_from_slice(&request_body_key);
-        header_buffer.push(random[32]);
-        header_buffer.push(request.option as u8);
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use uuid::Uuid;
 
-        let padding_len: u8 = rng.gen_range(0..16);
-        let security = padding_len << 4 | 5_u8;
-        header_buffer.push(security);
-        header_buffer.push(0);
-        header_buffer.push(request.command as u8);
+    use std::sync::Mutex;
+    use std::time::Duration;
 
-        // write address and port
+    use super::*;
+    use crate::aead::ReplayFilter;
+    use crate::env::{Env, FixedClock, RngSource, SeededRngSource};
+    use crate::{VMESSOptions, VmessServer};
 
-        // NOTE: port first, then address
-        // write address family
-        header_buffer.extend_from_slice(&443_u16.to_be_bytes());
-        header_buffer.push(1_u8);
-        // address is a 4 byte array
-        let address: [u8; 4] = [198, 18, 2, 124];
-        header_buffer.extend_from_slice(&address);
+    const KEY: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+    const IV: [u8; 16] = [
+        16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31,
+    ];
+    const DATA: &[u8] = b"VMess chunk data";
 
-        // read padding
-        let mut random = [0; 16];
-        rng.fill(&mut random);
-        header_buffer.extend_from_slice(&random[0..padding_len as usize]);
+    /// write DATA as the first chunk of a plain chunk stream, check it reads back
+    fn first_chunk(security: RequestSecurity) -> Vec<u8> {
+        let option = RequestOption::ChunkStream as u8;
+        let codec = ChunkCodec::new(security, RequestCommand::Tcp, option, &KEY, &IV);
+        let mut writer = ChunkWriter::new(codec, SeededRngSource(0).rng());
+        let mut buf = Vec::new();
+        writer.write_chunk(DATA, &mut buf);
 
-        // calculate fnv has
-        let fnv_hash = fnv(&header_buffer);
-        header_buffer.extend_from_slice(&fnv_hash.to_be_bytes());
+        let codec = ChunkCodec::new(security, RequestCommand::Tcp, option, &KEY, &IV);
+        let mut reader = ChunkReader::new(codec);
+        let mut src = buf.clone();
+        assert_eq!(reader.read_chunk(&mut src).unwrap().as_deref(), Some(DATA));
+        assert!(src.is_empty());
+        buf
+    }
 
-        let uuid = uuid!("231c2fc0-f8c4-4248-b098-21f0dd78c810");
-        let id = ID::new(uuid);
-        let aead_header = AEADHeader::new();
+    #[test]
+    fn chunk_none() {
+        let mut expected = vec![0x00, 0x10];
+        expected.extend_from_slice(DATA);
+        assert_eq!(first_chunk(RequestSecurity::None), expected);
+    }
 
-        let mut vmess_out = aead_header.seal(id, &header_buffer);
-        vmess_out.extend_from_slice(buf);
+    #[test]
+    fn chunk_aes128gcm() {
+        assert_eq!(
+            first_chunk(RequestSecurity::AES128GCM),
+            [
+                0x00, 0x20, 0x9d, 0x3e, 0xcd, 0xca, 0x9a, 0x91, 0xd3, 0x16, 0x39, 0x7a, 0x60, 0xa3,
+                0x4e, 0x00, 0xd2, 0xbe, 0x01, 0x00, 0x2f, 0x21, 0xf7, 0xbc, 0xe5, 0x4c, 0xd9, 0x86,
+                0xae, 0x4a, 0x2e, 0x32, 0xf1, 0xe6
+            ]
+        );
+    }
 
-        Pin::new(&mut self.get_mut().stream).poll_write(cx, &vmess_out)
+    #[test]
+    fn chunk_chacha20poly1305() {
+        assert_eq!(
+            first_chunk(RequestSecurity::CHACHA20POLY1305),
+            [
+                0x00, 0x20, 0xe9, 0xde, 0x6f, 0x49, 0x18, 0x7c, 0xe0, 0xd0, 0x0d, 0x0e, 0x3c, 0x4d,
+                0x4d, 0x33, 0x01, 0x79, 0x85, 0x87, 0x1d, 0x95, 0x87, 0x8d, 0x7a, 0x35, 0x3a, 0x57,
+                0x90, 0x5e, 0x4c, 0x42, 0xea, 0x1e
+            ]
+        );
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
-        Poll::Ready(Err(io::Error::new(
-            io::ErrorKind::Other,
-            "VMESSStream::poll_flush not implemented",
-        )))
+    /// time of the golden handshakes
+    const NOW: i64 = 1792283399;
+
+    /// a user whose connections always draw the same time and random bytes
+    fn fixed_user(alter_id: u16) -> VmessUser {
+        let uuid = Uuid::parse_str("231c2fc0-f8c4-4248-b098-21f0dd78c810").unwrap();
+        let mut user = VmessUser::new(uuid)
+            .with_alter_id(alter_id)
+            .with_security(Encryption::AES128GCM);
+        user.options = vec![VMESSOptions::M, VMESSOptions::P];
+        user.env = Env::new(FixedClock(NOW), SeededRngSource(0));
+        user
     }
 
-    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
-        Poll::Ready(Err(io::Error::new(
-            io::ErrorKind::Other,
-            "VMESSStream::poll_shutdown not implemented",
-        )))
+    /// everything sent by the client for DATA to 127.0.0.1:18001: the request header and one chunk
+    async fn handshake(user: &VmessUser) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = Address::SocketAddr(([127, 0, 0, 1], 18001).into());
+        let mut stream = VMESSStream::connect(listener.local_addr().unwrap(), target, user)
+            .await
+            .unwrap();
+        stream.write_all(DATA).await.unwrap();
+        stream.flush().await.unwrap();
+        drop(stream);
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut sent = Vec::new();
+        server.read_to_end(&mut sent).await.unwrap();
+        sent
     }
-}
 
-impl AsyncRead for VMESSStream {
-    fn poll_read(
-        self: Pin<&mut Self>,
-        cx: &mut task::Context<'_>,
-        buf: &mut ReadBuf<'_>,
-    ) -> task::Poll<Result<(), io::Error>> {
-        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    #[tokio::test]
+    async fn auto_security_is_resolved() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let user = VmessUser::new(Uuid::from_u128(1)).with_security(Encryption::AUTO);
+        let target = Address::SocketAddr(([127, 0, 0, 1], 18001).into());
+        let stream = VMESSStream::connect(listener.local_addr().unwrap(), target, &user)
+            .await
+            .unwrap();
+        assert!(matches!(
+            stream.security(),
+            Encryption::AES128GCM | Encryption::CHACHA20POLY1305
+        ));
+    }
+
+    #[tokio::test]
+    async fn handshake_aead() {
+        assert_eq!(
+            handshake(&fixed_user(0)).await,
+            [
+                0x39, 0xb3, 0xda, 0xfd, 0xdb, 0x57, 0x3d, 0x91, 0x6d, 0xbf, 0x63, 0xe6, 0x3f, 0x66,
+                0x8f, 0xed, 0x4a, 0x53, 0x96, 0x0f, 0x83, 0x56, 0x25, 0x44, 0x32, 0x02, 0x84, 0x07,
+                0xc5, 0x6d, 0xdb, 0x90, 0xed, 0x29, 0x79, 0xb1, 0x97, 0xf3, 0xa8, 0x8d, 0xd0, 0xd8,
+                0xcb, 0x7b, 0x44, 0x6c, 0x65, 0xb4, 0xae, 0x63, 0x5e, 0xad, 0xc0, 0x7f, 0xa0, 0x0a,
+                0xd9, 0xc6, 0x3c, 0xc1, 0x6d, 0xa9, 0x2e, 0x63, 0x4d, 0x8b, 0xa8, 0x09, 0xfa, 0xaf,
+                0x1d, 0x1f, 0xa2, 0x01, 0x67, 0xe5, 0x2f, 0x52, 0x2b, 0xd5, 0xa9, 0x12, 0xad, 0x78,
+                0xf5, 0xd1, 0x0e, 0x53, 0xae, 0xc5, 0x1a, 0x3e, 0x6c, 0xd6, 0xe7, 0xc0, 0x4b, 0xeb,
+                0x70, 0x06, 0x7e, 0x20, 0x68, 0x32, 0x2e, 0x34, 0x8b, 0x7b, 0x45, 0x7c, 0xf8, 0xde,
+                0x26, 0x7e, 0x86, 0xf4, 0x92, 0x34, 0x4b, 0x03, 0x69, 0xaf, 0x69, 0xb1, 0x5b, 0x30,
+                0xa9, 0xa3, 0x98, 0xe2, 0xa6, 0xab, 0x8d, 0x08, 0x8e, 0x22, 0xd1, 0xf1, 0xd4, 0xb0,
+                0x72, 0x71, 0xf7, 0x3d, 0xb0, 0x26, 0x79, 0x09, 0xe0, 0x95, 0x7a, 0x2f, 0x92, 0x2b,
+                0x58, 0xe7, 0x96, 0x46, 0xe0, 0x2a, 0x25, 0x29, 0xcb, 0x7c, 0x99, 0xe3
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_legacy() {
+        assert_eq!(
+            handshake(&fixed_user(4)).await,
+            [
+                0x9e, 0x47, 0xe5, 0x73, 0x3c, 0x7d, 0xa1, 0xfb, 0x1a, 0x1c, 0xd3, 0xa7, 0xc9, 0x76,
+                0x22, 0x38, 0xf8, 0x58, 0xa5, 0x60, 0x88, 0x99, 0xae, 0xbc, 0x65, 0xe6, 0x44, 0x6f,
+                0x67, 0x2a, 0x82, 0x58, 0x5e, 0xb1, 0x02, 0x1b, 0x26, 0x4b, 0x54, 0x9c, 0xf7, 0xa8,
+                0x32, 0x6c, 0x28, 0xe0, 0x48, 0xcd, 0x3d, 0x92, 0xb3, 0x37, 0x0a, 0xf5, 0xca, 0x6e,
+                0xa8, 0x1d, 0x53, 0xbb, 0x0f, 0xf4, 0x45, 0xaa, 0x2a, 0xa5, 0xad, 0xa9, 0xf6, 0xc0,
+                0x3c, 0x7e, 0x86, 0xf4, 0x92, 0x34, 0x4b, 0x03, 0x69, 0xaf, 0x69, 0xb1, 0x5b, 0x30,
+                0xa9, 0xa3, 0x98, 0xe2, 0xa6, 0xab, 0x8d, 0x08, 0x8e, 0x22, 0xd1, 0xf1, 0xd4, 0xb0,
+                0x72, 0x71, 0xf7, 0x3d, 0xb0, 0x26, 0x79, 0xd0, 0x8b, 0x43, 0xa3, 0xd8, 0x04, 0x57,
+                0xbf, 0x09, 0xe0, 0x95, 0x7a, 0x2f, 0x92, 0x2b, 0x58, 0xe7, 0x96, 0x46
+            ]
+        );
+    }
+
+    const REPLY: &[u8] = b"VMess reply data";
+
+    /// users of each chunk mode: every security, with and without masking and padding
+    fn chunk_mode_users() -> Vec<VmessUser> {
+        let mut users = Vec::new();
+        for security in [
+            Encryption::NONE,
+            Encryption::AES128GCM,
+            Encryption::CHACHA20POLY1305,
+        ] {
+            for options in [vec![], vec![VMESSOptions::M, VMESSOptions::P]] {
+                let mut user = VmessUser::new(Uuid::from_u128(1)).with_security(security.clone());
+                user.options = options;
+                users.push(user);
+            }
+        }
+        users
+    }
+
+    /// connect user to a local listener, return the client and the accepted connection
+    async fn connect_local(user: &VmessUser) -> (VMESSStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = Address::SocketAddr(([127, 0, 0, 1], 18001).into());
+        let client = VMESSStream::connect(listener.local_addr().unwrap(), target, user)
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    /// read the request header of user on the server side
+    async fn read_request(server: &mut TcpStream, user: &VmessUser) -> (RequestHeader, Session) {
+        let replay_filter = Mutex::new(ReplayFilter::new());
+        VmessServer::read_request(server, &[user.id()], &[], &replay_filter, &user.env)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn shutdown_writes_end_chunk() {
+        for user in chunk_mode_users() {
+            let (mut client, mut server) = connect_local(&user).await;
+            client.write_all(DATA).await.unwrap();
+            client.shutdown().await.unwrap();
+
+            let (request, session) = read_request(&mut server, &user).await;
+            let mut body = Vec::new();
+            server.read_to_end(&mut body).await.unwrap();
+            let codec = ChunkCodec::new(
+                request.security,
+                request.command,
+                request.option,
+                &session.request_body_key,
+                &session.request_body_iv,
+            );
+            let mut reader = ChunkReader::new(codec);
+            let mut src = body;
+            assert_eq!(reader.read_chunk(&mut src).unwrap().as_deref(), Some(DATA));
+            assert_eq!(
+                reader.read_chunk(&mut src).unwrap(),
+                Some(vec![]),
+                "{:?}",
+                user
+            );
+            assert!(src.is_empty(), "{:?}", user);
+        }
+    }
+
+    #[tokio::test]
+    async fn end_chunk_reads_as_eof() {
+        for user in chunk_mode_users() {
+            let (mut client, mut server) = connect_local(&user).await;
+            client.write_all(DATA).await.unwrap();
+            client.shutdown().await.unwrap();
+            let (request, session) = read_request(&mut server, &user).await;
+            let mut body = Vec::new();
+            server.read_to_end(&mut body).await.unwrap();
+
+            // the same body on a connection left open, with data after the end chunk
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut peer = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            body.extend_from_slice(b"after the end");
+            peer.write_all(&body).await.unwrap();
+            let session = Session::from_request(
+                HeaderMode::Aead,
+                session.request_body_key,
+                session.request_body_iv,
+                session.response_header,
+            );
+            let mut server = VMESSStream::accept(accepted, &request, session, user.env.rng());
+            let mut received = Vec::new();
+            tokio::time::timeout(Duration::from_secs(1), server.read_to_end(&mut received))
+                .await
+                .expect("the end chunk ends the stream")
+                .unwrap();
+            assert_eq!(received, DATA, "{:?}", user);
+        }
+    }
+
+    #[tokio::test]
+    async fn half_close_keeps_the_response() {
+        for user in chunk_mode_users() {
+            let (mut client, mut server) = connect_local(&user).await;
+            client.write_all(DATA).await.unwrap();
+            client.shutdown().await.unwrap();
+
+            let (request, session) = read_request(&mut server, &user).await;
+            let mut server = VMESSStream::accept(server, &request, session, user.env.rng());
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, DATA, "{:?}", user);
+            server.write_all(REPLY).await.unwrap();
+            server.shutdown().await.unwrap();
+
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, REPLY, "{:?}", user);
+        }
     }
 }
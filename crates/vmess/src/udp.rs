@@ -0,0 +1,206 @@
+//! UDP over VMess
+//!
+//! A session is a VMess connection with the UDP command, relaying datagrams to one destination.
+//! Each datagram is carried by one chunk in both directions.
+
+use std::{fmt::Display, io};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::ToSocketAddrs,
+};
+
+use crate::{protocol::RequestCommand, stream::VMESSStream, Address, VmessUser};
+
+pub struct VMESSUdpSession {
+    stream: VMESSStream,
+}
+
+impl VMESSUdpSession {
+    /// connect to the VMess server at addr as user, the server will relay datagrams to target
+    pub async fn connect<A>(addr: A, target: Address, user: &VmessUser) -> io::Result<Self>
+    where
+        A: ToSocketAddrs + Display,
+    {
+        let stream = VMESSStream::connect_command(addr, RequestCommand::Udp, target, user).await?;
+        Ok(Self { stream })
+    }
+
+    /// destination of the datagrams
+    pub fn target(&self) -> &Address {
+        &self.stream.target
+    }
+
+    /// send one datagram to the target, empty datagrams are dropped
+    /// since an empty chunk ends the session
+    pub async fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        send(&mut self.stream, datagram).await
+    }
+
+    /// receive one datagram from the target, it is truncated if buf is too small,
+    /// return 0 when the session is closed by the server
+    pub async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf).await
+    }
+
+    /// tell the server that no more datagrams will be sent
+    pub async fn close(&mut self) -> io::Result<()> {
+        self.stream.shutdown().await
+    }
+
+    /// split the session so that datagrams can be sent and received concurrently
+    pub fn split(self) -> (VMESSUdpSendHalf, VMESSUdpRecvHalf) {
+        let (reader, writer) = tokio::io::split(self.stream);
+        (VMESSUdpSendHalf { writer }, VMESSUdpRecvHalf { reader })
+    }
+}
+
+/// Sending half of a `VMESSUdpSession`
+pub struct VMESSUdpSendHalf {
+    writer: WriteHalf<VMESSStream>,
+}
+
+impl VMESSUdpSendHalf {
+    /// see `VMESSUdpSession::send`
+    pub async fn send(&mut self, datagram: &[u8]) -> io::Result<()> {
+        send(&mut self.writer, datagram).await
+    }
+
+    /// see `VMESSUdpSession::close`
+    pub async fn close(&mut self) -> io::Result<()> {
+        self.writer.shutdown().await
+    }
+}
+
+/// Receiving half of a `VMESSUdpSession`
+pub struct VMESSUdpRecvHalf {
+    reader: ReadHalf<VMESSStream>,
+}
+
+impl VMESSUdpRecvHalf {
+    /// see `VMESSUdpSession::recv`
+    pub async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf).await
+    }
+}
+
+async fn send<W>(writer: &mut W, datagram: &[u8]) -> io::Result<()>
+where
+    W: AsyncWriteExt + Unpin,
+{
+    if datagram.is_empty() {
+        return Ok(());
+    }
+    // the stream accepts the whole datagram at once, encoded into one chunk
+    writer.write_all(datagram).await?;
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::net::{TcpListener, TcpStream};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{
+        aead::ReplayFilter,
+        protocol::RequestHeader,
+        stream::{ChunkCodec, ChunkReader, Session, MAX_DATAGRAM_SIZE},
+        Encryption, VMESSOptions, VmessServer,
+    };
+
+    fn user() -> VmessUser {
+        let mut user = VmessUser::new(Uuid::from_u128(1)).with_security(Encryption::AES128GCM);
+        user.options = vec![VMESSOptions::M, VMESSOptions::P];
+        user
+    }
+
+    /// open a session of user to a local listener, return it and the accepted connection
+    async fn connect_local(user: &VmessUser) -> (VMESSUdpSession, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = Address::SocketAddr(([127, 0, 0, 1], 53).into());
+        let session = VMESSUdpSession::connect(listener.local_addr().unwrap(), target, user)
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (session, server)
+    }
+
+    async fn read_request(server: &mut TcpStream, user: &VmessUser) -> (RequestHeader, Session) {
+        let replay_filter = Mutex::new(ReplayFilter::new());
+        VmessServer::read_request(server, &[user.id()], &[], &replay_filter, &user.env)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn one_chunk_per_datagram() {
+        let user = user();
+        let (mut session, mut server) = connect_local(&user).await;
+        // larger than a chunk of a tcp stream
+        let datagrams = [vec![1u8; 10], vec![2u8; 10_000], vec![3u8; 1]];
+        for datagram in datagrams.iter() {
+            session.send(datagram).await.unwrap();
+        }
+        // dropped, an empty chunk would end the session
+        session.send(&[]).await.unwrap();
+        session.close().await.unwrap();
+
+        let (request, keys) = read_request(&mut server, &user).await;
+        assert_eq!(request.command, RequestCommand::Udp);
+        let mut body = Vec::new();
+        server.read_to_end(&mut body).await.unwrap();
+        let mut reader = ChunkReader::new(ChunkCodec::new(
+            request.security,
+            request.command,
+            request.option,
+            &keys.request_body_key,
+            &keys.request_body_iv,
+        ));
+        for datagram in datagrams.iter() {
+            assert_eq!(
+                reader.read_chunk(&mut body).unwrap().as_ref(),
+                Some(datagram)
+            );
+        }
+        assert_eq!(reader.read_chunk(&mut body).unwrap(), Some(vec![]));
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn split_halves_keep_datagram_boundaries() {
+        let user = user();
+        let (session, mut server) = connect_local(&user).await;
+        let (mut send, mut recv) = session.split();
+        send.send(b"query").await.unwrap();
+
+        let (request, keys) = read_request(&mut server, &user).await;
+        let mut server = VMESSStream::accept(server, &request, keys, user.env.rng());
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let n = server.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"query");
+        server.write_all(b"first answer").await.unwrap();
+        server.write_all(b"second").await.unwrap();
+        server.shutdown().await.unwrap();
+
+        let n = recv.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"first answer");
+        let n = recv.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"second");
+        assert_eq!(recv.recv(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn oversize_datagram_is_rejected() {
+        let user = user();
+        let (mut session, _server) = connect_local(&user).await;
+        let e = session
+            .send(&vec![0u8; MAX_DATAGRAM_SIZE + 1])
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        session.send(&vec![0u8; MAX_DATAGRAM_SIZE]).await.unwrap();
+    }
+}